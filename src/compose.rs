@@ -0,0 +1,522 @@
+use crate::config::{self, PortSpec};
+use crate::error::{Error, Result};
+use crate::port_finder::Protocol;
+use serde_yaml::value::{Tag, TaggedValue};
+use serde_yaml::{Mapping, Value};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Compose files looked for in a directory, in the order `docker compose` uses.
+pub const COMPOSE_FILES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+/// The first of [`COMPOSE_FILES`] that exists in `dir`.
+pub fn find_file(dir: &Path) -> Option<PathBuf> {
+    COMPOSE_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// The override file `docker compose` picks up next to `compose`, e.g.
+/// `docker-compose.override.yml` for `docker-compose.yml`.
+pub fn override_path(compose: &Path) -> PathBuf {
+    let stem = compose.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match compose.extension() {
+        Some(ext) => format!("{}.override.{}", stem, ext.to_string_lossy()),
+        None => format!("{}.override.yml", stem),
+    };
+    compose.with_file_name(name)
+}
+
+/// A port a compose service publishes on the host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishedPort {
+    pub service: String,
+    /// Port (or range) inside the container
+    pub target: PortSpec,
+    pub protocol: Protocol,
+    /// The entry as written, so the override keeps its host IP and other settings
+    entry: Entry,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Entry {
+    /// `"[HOST_IP:][HOST:]CONTAINER[/PROTOCOL]"`
+    Short { host_ip: Option<String> },
+    /// `{ target: 80, published: "8080", ... }`
+    Long(Mapping),
+}
+
+impl PublishedPort {
+    /// Name of the mapping holding the host port, e.g. `web/80`.
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.service, self.target.display())
+    }
+
+    /// Number of host ports needed.
+    pub fn count(&self) -> u16 {
+        let (start, end) = self.target.bounds();
+        end - start + 1
+    }
+
+    /// The entry rewritten to publish on `host`.
+    fn remapped(&self, host: &PortSpec) -> Value {
+        match &self.entry {
+            Entry::Short { host_ip } => {
+                let mut entry = String::new();
+                if let Some(ip) = host_ip {
+                    entry.push_str(ip);
+                    entry.push(':');
+                }
+                entry.push_str(&format!("{}:{}", host.display(), self.target.display()));
+                if self.protocol == Protocol::Udp {
+                    entry.push_str("/udp");
+                }
+                Value::String(entry)
+            }
+            Entry::Long(entry) => {
+                let mut entry = entry.clone();
+                entry.insert("published".into(), Value::String(host.display()));
+                Value::Mapping(entry)
+            }
+        }
+    }
+}
+
+/// A port entry that couldn't be understood, e.g. `"${PORT}:80"`. It is
+/// carried over to the override unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skipped {
+    pub service: String,
+    pub entry: Value,
+    pub reason: String,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.service, self.reason)
+    }
+}
+
+/// The parts of a compose file `compose` cares about.
+#[derive(Debug, Default)]
+pub struct ComposeFile {
+    /// Top-level `name`, or the directory name like `docker compose` uses
+    pub project: String,
+    pub ports: Vec<PublishedPort>,
+    pub skipped: Vec<Skipped>,
+}
+
+/// Reads the compose file at `path`; see [`parse`].
+pub fn load(path: &Path) -> Result<ComposeFile> {
+    let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    parse(path, &content)
+}
+
+/// Reads the published ports of every service in the compose file at `path`.
+pub fn parse(path: &Path, content: &str) -> Result<ComposeFile> {
+    let invalid = |message: String| Error::Validation(format!("{}: {}", path.display(), message));
+    let root: Value = serde_yaml::from_str(content).map_err(|e| invalid(e.to_string()))?;
+
+    let project = match root.get("name").and_then(Value::as_str) {
+        Some(name) => name.to_string(),
+        None => path
+            .canonicalize()
+            .ok()
+            .and_then(|path| Some(path.parent()?.file_name()?.to_string_lossy().into_owned()))
+            .map(|dir| project_name(&dir))
+            .unwrap_or_else(|| "default".to_string()),
+    };
+
+    let mut file = ComposeFile {
+        project,
+        ..Default::default()
+    };
+    let Some(services) = root.get("services") else {
+        return Ok(file);
+    };
+    let services = services
+        .as_mapping()
+        .ok_or_else(|| invalid("'services' is not a mapping".to_string()))?;
+    for (service, definition) in services {
+        let Some(service) = service.as_str() else {
+            continue;
+        };
+        let Some(ports) = definition.get("ports").and_then(Value::as_sequence) else {
+            continue;
+        };
+        for entry in ports {
+            match parse_entry(service, entry) {
+                Ok(port) => file.ports.push(port),
+                Err(reason) => file.skipped.push(Skipped {
+                    service: service.to_string(),
+                    entry: entry.clone(),
+                    reason,
+                }),
+            }
+        }
+    }
+    Ok(file)
+}
+
+fn parse_entry(service: &str, entry: &Value) -> std::result::Result<PublishedPort, String> {
+    let (target, protocol, entry) = match entry {
+        Value::Number(port) => {
+            let target = port
+                .as_u64()
+                .and_then(|port| u16::try_from(port).ok())
+                .filter(|port| *port > 0)
+                .ok_or_else(|| format!("invalid port {}", port))?;
+            (
+                PortSpec::Single(target),
+                Protocol::Tcp,
+                Entry::Short { host_ip: None },
+            )
+        }
+        Value::String(spec) => {
+            let (spec, protocol) = match spec.rsplit_once('/') {
+                Some((spec, protocol)) => (spec, parse_protocol(protocol)?),
+                None => (spec.as_str(), Protocol::Tcp),
+            };
+            // The host IP may be bracketed IPv6, e.g. `[::1]:8080:80`
+            let (host_ip, ports) = match spec.strip_prefix('[') {
+                Some(rest) => {
+                    let (ip, ports) = rest
+                        .split_once("]:")
+                        .ok_or_else(|| format!("can't parse port '{}'", spec))?;
+                    (Some(format!("[{}]", ip)), ports)
+                }
+                None if spec.matches(':').count() == 2 => {
+                    let (ip, ports) = spec.split_once(':').unwrap_or_default();
+                    (Some(ip.to_string()), ports)
+                }
+                None => (None, spec),
+            };
+            let container = ports.rsplit(':').next().unwrap_or_default();
+            let target =
+                PortSpec::parse(container).map_err(|_| format!("can't parse port '{}'", spec))?;
+            (target, protocol, Entry::Short { host_ip })
+        }
+        Value::Mapping(entry) => {
+            let target = match entry.get("target") {
+                Some(Value::Number(port)) => port
+                    .as_u64()
+                    .and_then(|port| u16::try_from(port).ok())
+                    .map(PortSpec::Single),
+                Some(Value::String(port)) => PortSpec::parse(port).ok(),
+                _ => None,
+            }
+            .ok_or_else(|| "port entry without a valid 'target'".to_string())?;
+            let protocol = match entry.get("protocol").and_then(Value::as_str) {
+                Some(protocol) => parse_protocol(protocol)?,
+                None => Protocol::Tcp,
+            };
+            (target, protocol, Entry::Long(entry.clone()))
+        }
+        _ => return Err("unsupported port entry".to_string()),
+    };
+    Ok(PublishedPort {
+        service: service.to_string(),
+        target,
+        protocol,
+        entry,
+    })
+}
+
+fn parse_protocol(protocol: &str) -> std::result::Result<Protocol, String> {
+    match protocol.to_ascii_lowercase().as_str() {
+        "tcp" => Ok(Protocol::Tcp),
+        "udp" => Ok(Protocol::Udp),
+        other => Err(format!("unsupported protocol '{}'", other)),
+    }
+}
+
+/// Normalizes a directory name the way `docker compose` derives project names.
+fn project_name(dir: &str) -> String {
+    dir.to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect()
+}
+
+/// A rendered override file, from [`override_content`].
+#[derive(Debug)]
+pub struct Override {
+    pub content: String,
+    /// Whether `content` differs from the file on disk
+    pub changed: bool,
+    /// Whether the file on disk has comments or formatting that writing
+    /// `content` would lose, i.e. it isn't in the form this module writes
+    pub hand_edited: bool,
+}
+
+/// [`render_override`] for the override file at `path`, if it exists.
+pub fn override_content(
+    path: &Path,
+    assigned: &[(PublishedPort, PortSpec)],
+    skipped: &[Skipped],
+) -> Result<Override> {
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => Some(existing),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(Error::io(path, e)),
+    };
+    let content = render_override(path, existing.as_deref(), assigned, skipped)?;
+    Ok(Override {
+        changed: existing.as_deref() != Some(content.as_str()),
+        hand_edited: existing
+            .as_deref()
+            .is_some_and(|existing| !is_generated(existing)),
+        content,
+    })
+}
+
+/// Whether `content` is exactly what re-serializing it produces, so a
+/// rewrite keeps everything in it.
+fn is_generated(content: &str) -> bool {
+    if content.trim().is_empty() {
+        return true;
+    }
+    serde_yaml::from_str::<Value>(content)
+        .ok()
+        .and_then(|value| serde_yaml::to_string(&value).ok())
+        .is_some_and(|rendered| rendered == content)
+}
+
+/// Writes content from [`override_content`] to the override file at `path`,
+/// replacing it atomically.
+pub fn write_override(path: &Path, content: &str) -> Result<()> {
+    config::replace_atomic(path, content).map_err(|e| Error::io(path, e))
+}
+
+/// Renders the override file: `existing` (if any) with the `ports` of each
+/// service in `assigned` replaced by entries publishing on the assigned host
+/// ports, followed by the service's `skipped` entries. Everything else in
+/// `existing` is kept.
+///
+/// The lists are tagged `!override` so they replace the compose file's ports
+/// instead of being appended to them (Compose 2.24.4 or later).
+pub fn render_override(
+    path: &Path,
+    existing: Option<&str>,
+    assigned: &[(PublishedPort, PortSpec)],
+    skipped: &[Skipped],
+) -> Result<String> {
+    let invalid = |message: String| Error::Validation(format!("{}: {}", path.display(), message));
+    let mut root = match existing {
+        Some(content) => match serde_yaml::from_str(content).map_err(|e| invalid(e.to_string()))? {
+            Value::Mapping(root) => root,
+            Value::Null => Mapping::new(),
+            _ => return Err(invalid("not a mapping".to_string())),
+        },
+        None => Mapping::new(),
+    };
+
+    let services = root
+        .entry("services".into())
+        .or_insert_with(|| Value::Mapping(Mapping::new()));
+    if services.is_null() {
+        *services = Value::Mapping(Mapping::new());
+    }
+    let services = services
+        .as_mapping_mut()
+        .ok_or_else(|| invalid("'services' is not a mapping".to_string()))?;
+
+    let mut ports: Vec<(&str, Vec<Value>)> = Vec::new();
+    for (port, host) in assigned {
+        let entry = port.remapped(host);
+        match ports
+            .iter_mut()
+            .find(|(service, _)| *service == port.service)
+        {
+            Some((_, entries)) => entries.push(entry),
+            None => ports.push((&port.service, vec![entry])),
+        }
+    }
+    for (service, entries) in &mut ports {
+        entries.extend(
+            skipped
+                .iter()
+                .filter(|s| s.service == *service)
+                .map(|s| s.entry.clone()),
+        );
+    }
+    for (service, entries) in ports {
+        let definition = services
+            .entry(service.into())
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+        if definition.is_null() {
+            *definition = Value::Mapping(Mapping::new());
+        }
+        let definition = definition
+            .as_mapping_mut()
+            .ok_or_else(|| invalid(format!("service '{}' is not a mapping", service)))?;
+        let tagged = TaggedValue {
+            tag: Tag::new("override"),
+            value: Value::Sequence(entries),
+        };
+        definition.insert("ports".into(), Value::Tagged(Box::new(tagged)));
+    }
+
+    Ok(serde_yaml::to_string(&root)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPOSE: &str = r#"
+name: shop
+services:
+  web:
+    image: nginx
+    ports:
+      - "8080:80"
+      - "127.0.0.1:8443:443"
+      - 9000
+  dns:
+    ports:
+      - "[::1]:5353:53/udp"
+      - target: 8125
+        published: "8125"
+        protocol: udp
+        mode: host
+  metrics:
+    ports:
+      - "9090-9091:8080-8081"
+      - "${API_PORT}:${API_TARGET}"
+  worker:
+    image: busybox
+"#;
+
+    fn parsed() -> ComposeFile {
+        parse(Path::new("compose.yaml"), COMPOSE).unwrap()
+    }
+
+    #[test]
+    fn test_parse_published_ports() {
+        let file = parsed();
+        assert_eq!(file.project, "shop");
+        let names: Vec<_> = file.ports.iter().map(|p| p.name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "web/80",
+                "web/443",
+                "web/9000",
+                "dns/53",
+                "dns/8125",
+                "metrics/8080-8081"
+            ]
+        );
+        assert_eq!(file.ports[3].protocol, Protocol::Udp);
+        assert_eq!(file.ports[4].protocol, Protocol::Udp);
+        assert_eq!(file.ports[5].count(), 2);
+        assert_eq!(file.skipped.len(), 1);
+        assert!(file.skipped[0]
+            .to_string()
+            .starts_with("metrics: can't parse port"));
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_files() {
+        assert!(parse(Path::new("compose.yaml"), "services: [web]").is_err());
+        assert!(parse(Path::new("compose.yaml"), "services:\n  - :").is_err());
+        let empty = parse(Path::new("compose.yaml"), "name: x\n").unwrap();
+        assert!(empty.ports.is_empty());
+    }
+
+    #[test]
+    fn test_render_override_remaps_and_is_idempotent() {
+        let file = parsed();
+        let assigned: Vec<_> = file
+            .ports
+            .iter()
+            .zip(41000..)
+            .map(|(port, host)| {
+                let host = match port.count() {
+                    1 => PortSpec::Single(host),
+                    n => PortSpec::Range {
+                        start: host,
+                        end: host + n - 1,
+                    },
+                };
+                (port.clone(), host)
+            })
+            .collect();
+
+        let existing = "services:\n  web:\n    environment:\n      DEBUG: \"1\"\n";
+        let path = Path::new("compose.override.yaml");
+        let rendered = render_override(path, Some(existing), &assigned, &file.skipped).unwrap();
+        assert_eq!(
+            rendered,
+            r#"services:
+  web:
+    environment:
+      DEBUG: '1'
+    ports: !override
+    - 41000:80
+    - 127.0.0.1:41001:443
+    - 41002:9000
+  dns:
+    ports: !override
+    - '[::1]:41003:53/udp'
+    - target: 8125
+      published: '41004'
+      protocol: udp
+      mode: host
+  metrics:
+    ports: !override
+    - 41005-41006:8080-8081
+    - ${API_PORT}:${API_TARGET}
+"#
+        );
+        assert_eq!(
+            render_override(path, Some(&rendered), &assigned, &file.skipped).unwrap(),
+            rendered
+        );
+    }
+
+    #[test]
+    fn test_override_content_detects_hand_edits() {
+        let dir =
+            std::env::temp_dir().join(format!("ports-manager-compose-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("compose.override.yaml");
+        let file = parsed();
+        let assigned = vec![(file.ports[0].clone(), PortSpec::Single(41000))];
+
+        let fresh = override_content(&path, &assigned, &[]).unwrap();
+        assert!(fresh.changed && !fresh.hand_edited);
+        write_override(&path, &fresh.content).unwrap();
+        let again = override_content(&path, &assigned, &[]).unwrap();
+        assert!(!again.changed && !again.hand_edited);
+
+        fs::write(&path, format!("# local tweaks\n{}", fresh.content)).unwrap();
+        let edited = override_content(&path, &assigned, &[]).unwrap();
+        assert!(edited.changed && edited.hand_edited);
+        assert!(!edited.content.contains("# local tweaks"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_override_path() {
+        assert_eq!(
+            override_path(Path::new("app/docker-compose.yml")),
+            Path::new("app/docker-compose.override.yml")
+        );
+        assert_eq!(
+            override_path(Path::new("compose.yaml")),
+            Path::new("compose.override.yaml")
+        );
+        assert_eq!(project_name("My App.v2"), "myappv2");
+    }
+}
@@ -1,8 +1,17 @@
+use crate::error::{Error, Result};
+use crate::expiry;
+use crate::lock::ConfigLock;
+use crate::port_finder::{self, Policy, Protocol};
+use crate::process::Owner;
+use crate::project::{ProjectFile, ProjectPort};
+use crate::sync;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum PortSpec {
     Single(u16),
@@ -10,7 +19,7 @@ pub enum PortSpec {
 }
 
 impl PortSpec {
-    pub fn parse(input: &str) -> Result<Self, String> {
+    pub fn parse(input: &str) -> std::result::Result<Self, String> {
         if let Some((start_str, end_str)) = input.split_once('-') {
             let start = start_str
                 .trim()
@@ -41,13 +50,216 @@ impl PortSpec {
             PortSpec::Range { start, end } => format!("{}-{}", start, end),
         }
     }
+
+    /// First and last port, equal for a single port.
+    pub fn bounds(&self) -> (u16, u16) {
+        match *self {
+            PortSpec::Single(port) => (port, port),
+            PortSpec::Range { start, end } => (start, end),
+        }
+    }
+
+    /// Whether the two specs share at least one port.
+    pub fn overlaps(&self, other: &PortSpec) -> bool {
+        let (start, end) = self.bounds();
+        let (other_start, other_end) = other.bounds();
+        start <= other_end && other_start <= end
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct PortMapping {
     pub name: String,
     pub port: PortSpec,
     pub description: Option<String>,
+    /// Project namespace; `None` for global mappings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    /// Protocol the port must be free for when it is allocated
+    #[serde(default, skip_serializing_if = "Protocol::is_tcp")]
+    pub protocol: Protocol,
+    /// Deliberately shares its port with other mappings, so overlaps aren't conflicts
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shared: bool,
+    /// When the port was auto-assigned (Unix seconds); `None` for mappings set by hand
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    /// When `get` or `exec` last resolved an auto-assigned mapping (Unix seconds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<u64>,
+    /// Seconds without use after which `gc` removes an auto-assigned mapping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// Process the auto-assigned mapping is released with when it exits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Owner>,
+}
+
+impl PortMapping {
+    pub fn new(name: &str, port: PortSpec, description: Option<&str>) -> Self {
+        PortMapping {
+            name: name.to_string(),
+            port,
+            description: description.map(str::to_string),
+            project: None,
+            protocol: Protocol::Tcp,
+            shared: false,
+            created: None,
+            last_used: None,
+            ttl: None,
+            owner: None,
+        }
+    }
+
+    /// Whether `get` auto-assigned this mapping, making it eligible for `gc`.
+    pub fn is_auto(&self) -> bool {
+        self.created.is_some()
+    }
+
+    /// Whether `gc` should remove this mapping at `now`: it was auto-assigned
+    /// and has gone unused for longer than its TTL, or than `max_idle`.
+    pub fn is_stale(&self, now: u64, max_idle: Option<u64>) -> bool {
+        let Some(created) = self.created else {
+            return false;
+        };
+        let idle = now.saturating_sub(self.last_used.unwrap_or(created));
+        self.ttl.is_some_and(|ttl| idle > ttl) || max_idle.is_some_and(|max| idle > max)
+    }
+
+    /// Name including the project namespace, e.g. `myrepo/api`.
+    pub fn qualified_name(&self) -> String {
+        match &self.project {
+            Some(project) => format!("{}/{}", project, self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Whether this is a `pool/sub` lease of a port inside `pool`'s range.
+    pub fn is_lease_of(&self, pool: &PortMapping) -> bool {
+        let (PortSpec::Single(port), PortSpec::Range { start, end }) = (&self.port, &pool.port)
+        else {
+            return false;
+        };
+        self.name
+            .strip_prefix(&pool.name)
+            .is_some_and(|rest| rest.starts_with('/'))
+            && (*start..=*end).contains(port)
+    }
+}
+
+/// Which configuration file a mapping was resolved from.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MappingSource {
+    User,
+    /// Fixed port declared in the project's `.ports.toml`
+    Project,
+    /// Read-only mapping from the system-wide config.toml
+    System,
+    Default,
+}
+
+/// How [`Config::allocate`] came up with a mapping.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Allocated {
+    /// Already configured; nothing changed
+    Existing(MappingSource),
+    /// Newly leased from a range pool (`pool/sub`)
+    Leased,
+    /// Newly auto-assigned from the allocation range
+    Assigned,
+}
+
+/// How `get` picks ports for names that aren't configured yet (`[allocation]` in config.toml).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AllocationConfig {
+    #[serde(default = "default_allocation_start")]
+    pub start: u16,
+    #[serde(default = "default_allocation_end")]
+    pub end: u16,
+    #[serde(default)]
+    pub policy: Policy,
+    /// Ports and ranges that are never auto-assigned
+    #[serde(default)]
+    pub exclude: Vec<PortSpec>,
+    /// Also skip the OS ephemeral port range (Linux: ip_local_port_range)
+    #[serde(default)]
+    pub exclude_ephemeral: bool,
+}
+
+fn default_allocation_start() -> u16 {
+    8000
+}
+
+fn default_allocation_end() -> u16 {
+    65535
+}
+
+impl Default for AllocationConfig {
+    fn default() -> Self {
+        AllocationConfig {
+            start: default_allocation_start(),
+            end: default_allocation_end(),
+            policy: Policy::default(),
+            exclude: Vec::new(),
+            exclude_ephemeral: false,
+        }
+    }
+}
+
+/// Per-call changes to the [`AllocationConfig`], e.g. from `get --range`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AllocationOverrides {
+    /// Replaces `start`/`end`; a single port allows only that port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<PortSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<Policy>,
+    /// Added to the configured exclusions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<PortSpec>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exclude_ephemeral: bool,
+}
+
+impl AllocationOverrides {
+    /// `base` with these overrides applied.
+    pub fn apply(&self, base: &AllocationConfig) -> AllocationConfig {
+        let mut settings = base.clone();
+        if let Some(range) = &self.range {
+            (settings.start, settings.end) = range.bounds();
+        }
+        if let Some(policy) = self.policy {
+            settings.policy = policy;
+        }
+        settings.exclude.extend(self.exclude.iter().cloned());
+        settings.exclude_ephemeral |= self.exclude_ephemeral;
+        settings
+    }
+}
+
+impl AllocationConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Every port covered by `exclude` and, if enabled, the ephemeral range.
+    pub fn excluded_ports(&self) -> Vec<u16> {
+        let mut ports = Vec::new();
+        for spec in &self.exclude {
+            match spec {
+                PortSpec::Single(port) => ports.push(*port),
+                PortSpec::Range { start, end } => ports.extend(*start..=*end),
+            }
+        }
+        if self.exclude_ephemeral {
+            if let Some((start, end)) = port_finder::ephemeral_port_range() {
+                ports.extend(start..=end);
+            }
+        }
+        ports
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -56,76 +268,458 @@ pub struct Config {
     pub ports: Vec<PortMapping>,
     #[serde(default)]
     pub ignored_defaults: Vec<String>,
+    #[serde(default, skip_serializing_if = "AllocationConfig::is_default")]
+    pub allocation: AllocationConfig,
+    /// Project namespace that lookups and changes apply to; `None` for global.
+    #[serde(skip)]
+    pub scope: Option<String>,
+    /// The scope's `.ports.toml`, consulted between scoped and global mappings.
+    #[serde(skip)]
+    pub project_file: Option<ProjectFile>,
+    /// File this config was loaded from; the default location if `None`.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Global mappings from the system-wide config.toml, never saved.
+    #[serde(skip)]
+    pub(crate) system: Vec<PortMapping>,
+    /// Defaults kept in memory by the daemon; read from disk when `None`.
+    #[serde(skip)]
+    pub(crate) defaults: Option<DefaultsConfig>,
 }
 
 impl Config {
-    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_path = Self::config_path()?;
+    /// Loads the user config from its default location, layered over the
+    /// system-wide mappings.
+    ///
+    /// With `lock` held, the file is created on first use and an old one is
+    /// upgraded in place (keeping a `.bak`). Without it, upgrades only happen
+    /// in memory, so an unlocked reader can't overwrite a concurrent writer.
+    pub fn load(lock: Option<&ConfigLock>) -> Result<Self> {
+        let mut config = Self::load_upgrading(&Self::config_path()?, lock)?;
+        config.system = Self::load_system_ports()?;
+        Ok(config)
+    }
 
-        if !config_path.exists() {
-            // Create default config if it doesn't exist
-            let default_config = Config::default();
-            default_config.save()?;
-            return Ok(default_config);
+    /// Reads the config file at `path`, writing it back if it is missing or
+    /// old and `lock` is held.
+    fn load_upgrading(path: &Path, lock: Option<&ConfigLock>) -> Result<Self> {
+        let (config, schema) = Self::read(path)?;
+        if lock.is_some() && schema.map_or(true, |schema| schema < CONFIG_SCHEMA) {
+            config.save()?;
         }
-
-        let content = fs::read_to_string(&config_path)?;
-        let config: Config = toml::from_str(&content)?;
         Ok(config)
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let config_path = Self::config_path()?;
+    /// Global mappings from `config.toml` in [`Config::system_dir`]; empty
+    /// when there is no such file.
+    pub fn load_system_ports() -> Result<Vec<PortMapping>> {
+        let Some(dir) = Self::system_dir() else {
+            return Ok(Vec::new());
+        };
+        let path = dir.join(CONFIG_FILE);
+        let system: Config = match fs::read_to_string(&path) {
+            Ok(content) => parse_versioned(&path, &content, CONFIG_SCHEMA, CONFIG_MIGRATIONS)?.0,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::io(&path, e)),
+        };
+        Ok(system
+            .ports
+            .into_iter()
+            .filter(|p| p.project.is_none())
+            .collect())
+    }
+
+    /// Loads a config file from an explicit path. A missing file gives an
+    /// empty config; [`Config::save`] writes back to the same path, and
+    /// defaults are read from the `defaults.toml` next to it.
+    pub fn load_from(path: &Path) -> Result<Self> {
+        Self::read(path).map(|(config, _)| config)
+    }
+
+    /// Reads and migrates a config file, also returning the schema version it
+    /// was written with (`None` if it doesn't exist).
+    fn read(path: &Path) -> Result<(Self, Option<u32>)> {
+        let (mut config, schema): (Config, _) = match fs::read_to_string(path) {
+            Ok(content) => {
+                let (config, schema) =
+                    parse_versioned(path, &content, CONFIG_SCHEMA, CONFIG_MIGRATIONS)?;
+                (config, Some(schema))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Config::default(), None),
+            Err(e) => return Err(Error::io(path, e)),
+        };
+        config.path = Some(path.to_path_buf());
+        Ok((config, schema))
+    }
+
+    /// Writes the config back to the file it was loaded from.
+    pub fn save(&self) -> Result<()> {
+        let config_path = self.file_path()?;
 
         // Create config directory if it doesn't exist
         if let Some(parent) = config_path.parent() {
-            fs::create_dir_all(parent)?;
+            fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+        }
+
+        let content = versioned(CONFIG_SCHEMA, toml::to_string_pretty(self)?);
+        write_atomic(&config_path, &content).map_err(|e| Error::io(&config_path, e))
+    }
+
+    /// Restores config.toml and defaults.toml from their `.bak` copies, but
+    /// only those that exist and can't be loaded (corrupt, or written by a
+    /// newer version), so a good file is never rolled back.
+    ///
+    /// Returns the paths that were restored. Backups that are missing or not
+    /// valid TOML are skipped.
+    pub fn restore_backups() -> Result<Vec<PathBuf>> {
+        Self::restore_backups_in(&Self::config_dir()?)
+    }
+
+    /// [`Config::restore_backups`] for the files in `dir`.
+    fn restore_backups_in(dir: &Path) -> Result<Vec<PathBuf>> {
+        let config_path = dir.join(CONFIG_FILE);
+        let defaults_path = dir.join(DEFAULTS_FILE);
+        let broken = [
+            config_path.exists() && Self::read(&config_path).is_err(),
+            defaults_path.exists() && DefaultsConfig::read(&defaults_path).is_err(),
+        ];
+        let mut restored = Vec::new();
+        for (path, broken) in [config_path, defaults_path].into_iter().zip(broken) {
+            if broken && restore_backup(&path).map_err(|e| Error::io(&path, e))? {
+                restored.push(path);
+            }
         }
+        Ok(restored)
+    }
 
-        let content = toml::to_string_pretty(self)?;
-        fs::write(&config_path, content)?;
-        Ok(())
+    /// Default location of config.toml.
+    pub fn config_path() -> Result<PathBuf> {
+        Ok(Self::config_dir()?.join(CONFIG_FILE))
     }
 
-    fn config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
-        let home_dir = directories::BaseDirs::new()
-            .ok_or("Could not find home directory")?
-            .home_dir()
-            .to_path_buf();
+    /// Directory holding config.toml and defaults.toml, first match wins:
+    /// `$PORTS_MANAGER_CONFIG_DIR` (which `--config-dir` sets),
+    /// `$XDG_CONFIG_HOME/ports-manager`, then `~/.config/ports-manager`.
+    pub fn config_dir() -> Result<PathBuf> {
+        let home_dir = directories::BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf());
+        resolve_config_dir(
+            std::env::var_os(CONFIG_DIR_ENV),
+            std::env::var_os("XDG_CONFIG_HOME"),
+            home_dir,
+        )
+        .ok_or(Error::NoHomeDir)
+    }
 
-        Ok(home_dir
-            .join(".config")
-            .join("ports-manager")
-            .join("config.toml"))
+    /// Read-only system-wide directory (`/etc/ports-manager` on Unix, or
+    /// `$PORTS_MANAGER_SYSTEM_DIR`), consulted below the user's files.
+    pub fn system_dir() -> Option<PathBuf> {
+        match std::env::var_os(SYSTEM_DIR_ENV) {
+            Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+            _ => SYSTEM_DIR.map(PathBuf::from),
+        }
     }
 
-    fn config_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
-        let home_dir = directories::BaseDirs::new()
-            .ok_or("Could not find home directory")?
-            .home_dir()
-            .to_path_buf();
+    /// The file this config is saved to.
+    pub fn file_path(&self) -> Result<PathBuf> {
+        match &self.path {
+            Some(path) => Ok(path.clone()),
+            None => Self::config_path(),
+        }
+    }
 
-        Ok(home_dir.join(".config").join("ports-manager"))
+    /// Loads the defaults that apply to this config, from `defaults.toml` in
+    /// the same directory.
+    pub fn load_defaults(&self) -> Result<DefaultsConfig> {
+        if let Some(defaults) = &self.defaults {
+            return Ok(defaults.clone());
+        }
+        DefaultsConfig::load_from(&self.file_path()?.with_file_name(DEFAULTS_FILE))
     }
 
-    pub fn add_port(&mut self, name: String, port: PortSpec, description: Option<String>) {
+    /// Adds a mapping in the current scope and returns it for further tweaks.
+    pub fn add_port(
+        &mut self,
+        name: String,
+        port: PortSpec,
+        description: Option<String>,
+    ) -> &mut PortMapping {
         self.ports.push(PortMapping {
             name,
             port,
             description,
+            project: self.scope.clone(),
+            protocol: Protocol::Tcp,
+            shared: false,
+            created: None,
+            last_used: None,
+            ttl: None,
+            owner: None,
+        });
+        self.ports.last_mut().unwrap()
+    }
+
+    /// Like [`Config::add_port`], but stamped as auto-assigned at `now`.
+    fn add_auto_port(&mut self, name: &str, port: PortSpec, protocol: Protocol) -> PortMapping {
+        let now = expiry::now();
+        let mapping = self.add_port(name.to_string(), port, None);
+        mapping.protocol = protocol;
+        mapping.created = Some(now);
+        mapping.last_used = Some(now);
+        mapping.clone()
+    }
+
+    /// Records a use of the auto-assigned mapping `name` resolves to, and
+    /// sets its TTL if `ttl` is given. To keep frequent `get`s from rewriting
+    /// the file, `last_used` only moves in steps of [`TOUCH_INTERVAL`].
+    ///
+    /// Returns whether anything changed.
+    pub fn touch(&mut self, name: &str, ttl: Option<u64>, now: u64) -> bool {
+        let Some(mapping) = self.auto_mapping_mut(name) else {
+            return false;
+        };
+        let Some(created) = mapping.created else {
+            return false;
+        };
+
+        let mut changed = false;
+        if now
+            >= mapping
+                .last_used
+                .unwrap_or(created)
+                .saturating_add(TOUCH_INTERVAL)
+        {
+            mapping.last_used = Some(now);
+            changed = true;
+        }
+        if ttl.is_some() && mapping.ttl != ttl {
+            mapping.ttl = ttl;
+            changed = true;
+        }
+        changed
+    }
+
+    /// Binds the auto-assigned mapping `name` resolves to to `owner`, so
+    /// [`Config::release_orphans`] removes it once that process exits.
+    ///
+    /// Returns whether anything changed; mappings that weren't auto-assigned,
+    /// or are bound to another process that is still running, can't be bound.
+    pub fn bind(&mut self, name: &str, owner: Owner) -> Result<bool> {
+        let qualified = self.qualify(name);
+        let Some(mapping) = self.auto_mapping_mut(name) else {
+            return Err(Error::Validation(format!(
+                "'{}' was not auto-assigned, so it can't be bound to a process",
+                qualified
+            )));
+        };
+        if let Some(current) = mapping.owner.filter(|o| *o != owner && o.is_alive()) {
+            return Err(Error::Validation(format!(
+                "'{}' is bound to PID {}, which is still running",
+                qualified, current.pid
+            )));
+        }
+        let changed = mapping.owner != Some(owner);
+        mapping.owner = Some(owner);
+        Ok(changed)
+    }
+
+    /// The user mapping `name` resolves to, if it was auto-assigned.
+    fn auto_mapping_mut(&mut self, name: &str) -> Option<&mut PortMapping> {
+        let Some((resolved, MappingSource::User)) = self.resolve(name) else {
+            return None;
+        };
+        self.ports
+            .iter_mut()
+            .find(|p| p.name == resolved.name && p.project == resolved.project)
+            .filter(|p| p.is_auto())
+    }
+
+    /// Mappings in any project whose owning process has exited.
+    pub fn orphans(&self) -> Vec<PortMapping> {
+        self.ports
+            .iter()
+            .filter(|p| p.owner.is_some_and(|owner| !owner.is_alive()))
+            .cloned()
+            .collect()
+    }
+
+    /// Removes [`Config::orphans`], returning leases to their pools, and
+    /// returns what was removed. Changes are not saved.
+    pub fn release_orphans(&mut self) -> Vec<PortMapping> {
+        let orphans = self.orphans();
+        for orphan in &orphans {
+            self.remove_mapping(orphan);
+        }
+        orphans
+    }
+
+    /// Auto-assigned mappings in any project that are stale at `now` (see
+    /// [`PortMapping::is_stale`]).
+    pub fn stale_ports(&self, now: u64, max_idle: Option<u64>) -> Vec<PortMapping> {
+        self.ports
+            .iter()
+            .filter(|p| p.is_stale(now, max_idle))
+            .cloned()
+            .collect()
+    }
+
+    /// User mappings in any project without usage stamps: ones made with `set`,
+    /// and ones auto-assigned before usage was recorded. `gc` only considers
+    /// them when asked to, as the two can't be told apart.
+    pub fn unstamped_ports(&self) -> Vec<PortMapping> {
+        self.ports
+            .iter()
+            .filter(|p| !p.is_auto())
+            .cloned()
+            .collect()
+    }
+
+    /// Removes this exact mapping, wherever it is scoped.
+    pub fn remove_mapping(&mut self, mapping: &PortMapping) -> bool {
+        let original_len = self.ports.len();
+        self.ports.retain(|p| p != mapping);
+        self.ports.len() < original_len
+    }
+
+    /// Sets `name` in the current scope to `port`, updating the existing
+    /// mapping in place (and dropping any duplicates of it) or adding a new one.
+    ///
+    /// Returns the mapping as it was before, if there was one, and the mapping
+    /// now stored so the caller can adjust its other fields. A mapping set by
+    /// hand is pinned: it loses its auto-assigned timestamps, TTL and owner, so `gc`
+    /// leaves it alone.
+    pub fn upsert_port(
+        &mut self,
+        name: &str,
+        port: PortSpec,
+    ) -> (Option<PortMapping>, &mut PortMapping) {
+        let scope = self.scope.clone();
+        let matches = |p: &PortMapping| p.name == name && p.project == scope;
+        let Some(index) = self.ports.iter().position(matches) else {
+            return (None, self.add_port(name.to_string(), port, None));
+        };
+
+        let previous = self.ports[index].clone();
+        let mut seen = 0;
+        self.ports.retain(|p| {
+            if !matches(p) {
+                return true;
+            }
+            seen += 1;
+            seen == 1
         });
+        let mapping = &mut self.ports[index];
+        mapping.port = port;
+        mapping.created = None;
+        mapping.last_used = None;
+        mapping.ttl = None;
+        mapping.owner = None;
+        (Some(previous), mapping)
     }
 
+    /// Other mappings whose ports overlap `candidate`'s: user mappings in any
+    /// namespace, fixed project ports, system mappings, and defaults still in effect.
+    ///
+    /// The candidate's own entry, pool leases inside their pool, and overlaps
+    /// where either side is marked `shared` don't count.
+    pub fn conflicts(
+        &self,
+        candidate: &PortMapping,
+        defaults: &DefaultsConfig,
+    ) -> Vec<(PortMapping, MappingSource)> {
+        if candidate.shared {
+            return Vec::new();
+        }
+        let clashes = |other: &PortMapping| {
+            !other.shared
+                && other.port.overlaps(&candidate.port)
+                && !candidate.is_lease_of(other)
+                && !other.is_lease_of(candidate)
+        };
+
+        let mut found: Vec<(PortMapping, MappingSource)> = self
+            .ports
+            .iter()
+            .filter(|p| !(p.name == candidate.name && p.project == candidate.project))
+            .filter(|p| clashes(p))
+            .map(|p| (p.clone(), MappingSource::User))
+            .collect();
+        found.extend(
+            self.project_file_ports()
+                .into_iter()
+                .filter(|p| p.name != candidate.name && clashes(p))
+                .map(|p| (p, MappingSource::Project)),
+        );
+        found.extend(
+            self.system_ports()
+                .into_iter()
+                .filter(|s| s.name != candidate.name && clashes(s))
+                .map(|s| (s, MappingSource::System)),
+        );
+        found.extend(
+            self.effective_defaults(defaults)
+                .into_iter()
+                .filter(|d| d.name != candidate.name && clashes(d))
+                .map(|d| (d, MappingSource::Default)),
+        );
+        found
+    }
+
+    /// Removes mappings named `name` in the current scope only.
     pub fn remove_port(&mut self, name: &str) -> bool {
         let original_len = self.ports.len();
-        self.ports.retain(|p| p.name != name);
+        let scope = self.scope.clone();
+        self.ports
+            .retain(|p| !(p.name == name && p.project == scope));
         self.ports.len() < original_len
     }
 
     pub fn find_port(&self, name: &str) -> Option<PortMapping> {
+        self.resolve(name).map(|(mapping, _)| mapping)
+    }
+
+    /// Like [`Config::find_port`], but also reports where the mapping came from.
+    ///
+    /// Precedence, first match wins:
+    /// 1. user mappings scoped to the current project (including resolved `auto` ports)
+    /// 2. fixed ports from the project's `.ports.toml`; an unresolved `auto`
+    ///    entry stops here and returns `None` so the caller assigns a port
+    /// 3. global user mappings
+    /// 4. system-wide mappings
+    /// 5. defaults, unless ignored
+    pub fn resolve(&self, name: &str) -> Option<(PortMapping, MappingSource)> {
+        if self.scope.is_some() {
+            if let Some(mapping) = self
+                .ports
+                .iter()
+                .find(|p| p.name == name && p.project == self.scope)
+            {
+                return Some((mapping.clone(), MappingSource::User));
+            }
+        }
+
+        if let Some(file) = &self.project_file {
+            match file.services.get(name) {
+                Some(ProjectPort::Fixed(port)) => {
+                    let mut mapping = PortMapping::new(name, port.clone(), None);
+                    mapping.project = self.scope.clone();
+                    return Some((mapping, MappingSource::Project));
+                }
+                Some(ProjectPort::Auto) => return None,
+                None => {}
+            }
+        }
+
         // First check user config
-        if let Some(mapping) = self.ports.iter().find(|p| p.name == name) {
-            return Some(mapping.clone());
+        if let Some(mapping) = self
+            .ports
+            .iter()
+            .find(|p| p.name == name && p.project.is_none())
+        {
+            return Some((mapping.clone(), MappingSource::User));
+        }
+
+        if let Some(mapping) = self.system.iter().find(|p| p.name == name) {
+            return Some((mapping.clone(), MappingSource::System));
         }
 
         // Check if this default is ignored
@@ -134,24 +728,292 @@ impl Config {
         }
 
         // Then check defaults
-        if let Ok(defaults) = DefaultsConfig::load() {
+        if let Ok(defaults) = self.load_defaults() {
             if let Some(mapping) = defaults.ports.iter().find(|p| p.name == name) {
-                return Some(mapping.clone());
+                return Some((mapping.clone(), MappingSource::Default));
             }
         }
 
         None
     }
 
+    /// All user mappings, across every project.
     pub fn list_ports(&self) -> &[PortMapping] {
         &self.ports
     }
 
+    /// Mappings visible from the current scope: the scope's own plus global
+    /// ones that it doesn't shadow.
+    pub fn visible_ports(&self) -> Vec<PortMapping> {
+        let scoped = |p: &PortMapping| self.scope.is_some() && p.project == self.scope;
+        self.ports
+            .iter()
+            .filter(|p| {
+                scoped(p)
+                    || (p.project.is_none()
+                        && !self.ports.iter().any(|o| scoped(o) && o.name == p.name))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// System-wide mappings not overridden by a global user mapping.
+    pub fn system_ports(&self) -> Vec<PortMapping> {
+        self.system
+            .iter()
+            .filter(|s| {
+                !self
+                    .ports
+                    .iter()
+                    .any(|p| p.name == s.name && p.project.is_none())
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Fixed ports from `.ports.toml` not overridden by a scoped user mapping.
+    pub fn project_file_ports(&self) -> Vec<PortMapping> {
+        let Some(file) = &self.project_file else {
+            return Vec::new();
+        };
+        file.services
+            .iter()
+            .filter_map(|(name, port)| match port {
+                ProjectPort::Fixed(port) => Some((name, port)),
+                ProjectPort::Auto => None,
+            })
+            .filter(|(name, _)| {
+                !self
+                    .ports
+                    .iter()
+                    .any(|p| &p.name == *name && p.project == self.scope)
+            })
+            .map(|(name, port)| {
+                let mut mapping = PortMapping::new(name, port.clone(), None);
+                mapping.project = self.scope.clone();
+                mapping
+            })
+            .collect()
+    }
+
+    /// Defaults that `get` would actually return: not ignored and not shadowed
+    /// by a user or system mapping of the same name.
+    pub fn effective_defaults(&self, defaults: &DefaultsConfig) -> Vec<PortMapping> {
+        defaults
+            .ports
+            .iter()
+            .filter(|d| !self.ignored_defaults.contains(&d.name))
+            .filter(|d| {
+                !self
+                    .ports
+                    .iter()
+                    .any(|p| p.name == d.name && p.project.is_none())
+                    && !self.system.iter().any(|s| s.name == d.name)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// If `name` is `pool/sub` and `pool` resolves to a range, returns the pool mapping.
+    pub fn pool_of(&self, name: &str) -> Option<PortMapping> {
+        let (pool, sub) = name.rsplit_once('/')?;
+        if pool.is_empty() || sub.is_empty() {
+            return None;
+        }
+        self.find_port(pool)
+            .filter(|mapping| matches!(mapping.port, PortSpec::Range { .. }))
+    }
+
+    /// Sub-assignments leased from `pool`, in any project, that lie inside its range.
+    pub fn pool_leases(&self, pool: &PortMapping) -> Vec<&PortMapping> {
+        self.ports.iter().filter(|p| p.is_lease_of(pool)).collect()
+    }
+
+    /// Leases a single free port for `pool/sub` from inside the pool's range
+    /// and records it as a mapping, so removing it returns the port to the pool.
+    ///
+    /// Returns `Ok(None)` if `name` doesn't refer to a range pool.
+    pub fn lease_from_pool(&mut self, name: &str) -> Result<Option<PortMapping>> {
+        let Some(pool) = self.pool_of(name) else {
+            return Ok(None);
+        };
+        let PortSpec::Range { start, end } = pool.port else {
+            return Ok(None);
+        };
+
+        // Skip ports used by any other mapping, which includes earlier leases;
+        // only the pool itself (and layers it shadows) is left out
+        let taken = self
+            .used_ports_except(|p| p.name == pool.name && matches!(p.port, PortSpec::Range { .. }));
+
+        // Leases inherit the pool's protocol
+        let port = port_finder::find_available_port_with_policy(
+            start,
+            end,
+            &taken,
+            Policy::Lowest,
+            name,
+            pool.protocol,
+        )
+        .ok_or_else(|| {
+            Error::Exhausted(format!(
+                "No free port left in pool '{}' ({})",
+                pool.name,
+                pool.port.display()
+            ))
+        })?;
+        Ok(Some(self.add_auto_port(
+            name,
+            PortSpec::Single(port),
+            pool.protocol,
+        )))
+    }
+
+    /// Picks a free port for `name` that no mapping uses, following `settings`.
+    pub fn find_free_port(
+        &self,
+        name: &str,
+        settings: &AllocationConfig,
+        protocol: Protocol,
+    ) -> Option<u16> {
+        let mut exclude = self.get_used_ports();
+        exclude.extend(settings.excluded_ports());
+        port_finder::find_available_port_with_policy(
+            settings.start,
+            settings.end,
+            &exclude,
+            settings.policy,
+            name,
+            protocol,
+        )
+    }
+
+    /// Like [`Config::find_free_port`], but for `count` consecutive ports.
+    /// Blocks are always placed at the lowest fitting position.
+    pub fn find_free_block(
+        &self,
+        count: u16,
+        settings: &AllocationConfig,
+        protocol: Protocol,
+    ) -> Option<u16> {
+        let mut exclude = self.get_used_ports();
+        exclude.extend(settings.excluded_ports());
+        port_finder::find_available_block_in_range(
+            settings.start,
+            settings.end,
+            count,
+            &exclude,
+            protocol,
+        )
+    }
+
+    /// `name` as seen from the current scope, e.g. `myrepo/api`.
+    pub fn qualify(&self, name: &str) -> String {
+        match &self.scope {
+            Some(project) => format!("{}/{}", project, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Resolves `name`, or leases it from its pool or auto-assigns a free
+    /// port if it isn't configured yet. New mappings are added to the config
+    /// but not saved.
+    pub fn allocate(
+        &mut self,
+        name: &str,
+        settings: &AllocationConfig,
+        protocol: Protocol,
+    ) -> Result<(PortMapping, Allocated)> {
+        if let Some((mapping, source)) = self.resolve(name) {
+            return Ok((mapping, Allocated::Existing(source)));
+        }
+        if let Some(lease) = self.lease_from_pool(name)? {
+            return Ok((lease, Allocated::Leased));
+        }
+
+        let port = self
+            .find_free_port(&self.qualify(name), settings, protocol)
+            .ok_or_else(|| {
+                Error::Exhausted(format!(
+                    "No available ports found in {}-{}",
+                    settings.start, settings.end
+                ))
+            })?;
+        let mapping = self.add_auto_port(name, PortSpec::Single(port), protocol);
+        Ok((mapping, Allocated::Assigned))
+    }
+
+    /// Like [`Config::allocate`], but for a block of `count` consecutive ports.
+    /// An existing mapping of a different size is an error.
+    pub fn allocate_block(
+        &mut self,
+        name: &str,
+        count: u16,
+        settings: &AllocationConfig,
+        protocol: Protocol,
+    ) -> Result<(PortMapping, Allocated)> {
+        if let Some((mapping, source)) = self.resolve(name) {
+            let (start, end) = mapping.port.bounds();
+            let size = u32::from(end) - u32::from(start) + 1;
+            if size != u32::from(count) {
+                return Err(Error::Validation(format!(
+                    "'{}' is already mapped to {} ({} port(s)), not a block of {}",
+                    mapping.qualified_name(),
+                    mapping.port.display(),
+                    size,
+                    count
+                )));
+            }
+            return Ok((mapping, Allocated::Existing(source)));
+        }
+
+        let start = self
+            .find_free_block(count, settings, protocol)
+            .ok_or_else(|| {
+                Error::Exhausted(format!(
+                    "No block of {} consecutive free ports found",
+                    count
+                ))
+            })?;
+        let port = if count == 1 {
+            PortSpec::Single(start)
+        } else {
+            PortSpec::Range {
+                start,
+                end: start + (count - 1),
+            }
+        };
+        let mapping = self.add_auto_port(name, port, protocol);
+        Ok((mapping, Allocated::Assigned))
+    }
+
+    /// Removes `name` from the current scope, returning its port to the pool
+    /// if it was a lease. Changes are not saved.
+    pub fn release(&mut self, name: &str) -> Result<PortMapping> {
+        let mapping = self
+            .ports
+            .iter()
+            .find(|p| p.name == name && p.project == self.scope)
+            .cloned()
+            .ok_or_else(|| {
+                Error::NotFound(format!("Port mapping not found: {}", self.qualify(name)))
+            })?;
+        self.remove_port(name);
+        Ok(mapping)
+    }
+
     pub fn get_used_ports(&self) -> Vec<u16> {
-        let mut used_ports = Vec::new();
+        self.used_ports_except(|_| false)
+    }
 
-        // Get ports from user config
-        for mapping in &self.ports {
+    /// Like [`Config::get_used_ports`], leaving out mappings for which `skip`
+    /// returns true.
+    fn used_ports_except(&self, skip: impl Fn(&PortMapping) -> bool) -> Vec<u16> {
+        let mut used_ports = Vec::new();
+        let mut add = |mapping: &PortMapping| {
+            if skip(mapping) {
+                return;
+            }
             match &mapping.port {
                 PortSpec::Single(port) => used_ports.push(*port),
                 PortSpec::Range { start, end } => {
@@ -160,19 +1022,26 @@ impl Config {
                     }
                 }
             }
+        };
+
+        // Get ports from user config
+        for mapping in &self.ports {
+            add(mapping);
+        }
+
+        // Get ports fixed by the project file or system-wide
+        for mapping in self
+            .project_file_ports()
+            .into_iter()
+            .chain(self.system_ports())
+        {
+            add(&mapping);
         }
 
         // Get ports from defaults
-        if let Ok(defaults) = DefaultsConfig::load() {
+        if let Ok(defaults) = self.load_defaults() {
             for mapping in &defaults.ports {
-                match &mapping.port {
-                    PortSpec::Single(port) => used_ports.push(*port),
-                    PortSpec::Range { start, end } => {
-                        for port in *start..=*end {
-                            used_ports.push(port);
-                        }
-                    }
-                }
+                add(mapping);
             }
         }
 
@@ -180,14 +1049,234 @@ impl Config {
     }
 }
 
+/// Layout version of config.toml, stored as `schema_version`. Bump it
+/// together with a new step in `CONFIG_MIGRATIONS` when the format changes.
+pub const CONFIG_SCHEMA: u32 = 1;
+/// Layout version of defaults.toml; see [`CONFIG_SCHEMA`]. Unrelated to the
+/// file's `version`, which tracks the built-in defaults it was synced with.
+pub const DEFAULTS_SCHEMA: u32 = 1;
+
+const SCHEMA_KEY: &str = "schema_version";
+
+/// Upgrades a parsed file by one schema version; the step at index `n`
+/// turns version `n` into `n + 1`.
+pub(crate) type Migration = fn(&mut toml::Table);
+
+pub(crate) const CONFIG_MIGRATIONS: &[Migration] = &[from_unversioned];
+pub(crate) const DEFAULTS_MIGRATIONS: &[Migration] = &[from_unversioned];
+
+/// Files written before schema versions (version 0) already match version 1.
+fn from_unversioned(_table: &mut toml::Table) {}
+
+/// Parses `content`, first running it through `migrations` if it was written
+/// with an older schema. Returns the value and the schema version on disk.
+///
+/// Files from a newer schema are refused rather than misread.
+pub(crate) fn parse_versioned<T: serde::de::DeserializeOwned>(
+    path: &Path,
+    content: &str,
+    current: u32,
+    migrations: &[Migration],
+) -> Result<(T, u32)> {
+    let mut table: toml::Table =
+        toml::from_str(content).map_err(|e| parse_error(path, content, e))?;
+    let found = schema_version(&table).ok_or_else(|| {
+        Error::Validation(format!(
+            "{}: {} must be a non-negative integer",
+            path.display(),
+            SCHEMA_KEY
+        ))
+    })?;
+    if found > current {
+        return Err(Error::UnsupportedSchema {
+            path: path.to_path_buf(),
+            found,
+            supported: current,
+        });
+    }
+    if found == current {
+        // Straight from the text, so errors keep their line numbers
+        let value = toml::from_str(content).map_err(|e| parse_error(path, content, e))?;
+        return Ok((value, found));
+    }
+
+    for migrate in &migrations[found as usize..current as usize] {
+        migrate(&mut table);
+    }
+    table.insert(SCHEMA_KEY.to_string(), toml::Value::Integer(current.into()));
+    let value = toml::Value::Table(table)
+        .try_into()
+        .map_err(|e| parse_error(path, content, e))?;
+    Ok((value, found))
+}
+
+/// The `schema_version` of a parsed file: 0 if absent, `None` if invalid.
+pub fn schema_version(table: &toml::Table) -> Option<u32> {
+    match table.get(SCHEMA_KEY) {
+        None => Some(0),
+        Some(toml::Value::Integer(version)) => u32::try_from(*version).ok(),
+        Some(_) => None,
+    }
+}
+
+/// Serialized file content with the schema version as its first line.
+fn versioned(schema: u32, body: String) -> String {
+    format!("{} = {}\n{}", SCHEMA_KEY, schema, body)
+}
+
+/// Path of the backup kept next to a config file, e.g. `config.toml.bak`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    path.with_file_name(name)
+}
+
+fn is_valid_toml(content: &str) -> bool {
+    content.parse::<toml::Table>().is_ok()
+}
+
+/// Writes `content` to `path` so that readers only ever see the old or the new file.
+///
+/// The previous version is kept as a `.bak` copy, unless it is itself
+/// unparseable, so a good backup is never replaced by a corrupt one.
+fn write_atomic(path: &Path, content: &str) -> io::Result<()> {
+    if let Ok(previous) = fs::read_to_string(path) {
+        if is_valid_toml(&previous) {
+            fs::copy(path, backup_path(path))?;
+        }
+    }
+    replace_atomic(path, content)
+}
+
+/// Replaces `path` with `content` without a window where the file is missing
+/// or half-written: the data is written to a temporary file in the same
+/// directory, fsynced and renamed over the target.
+pub(crate) fn replace_atomic(path: &Path, content: &str) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".tmp.{}", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&tmp_path, path)?;
+        sync_parent_dir(path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Replaces `path` with its `.bak` copy if the backup exists and parses.
+fn restore_backup(path: &Path) -> io::Result<bool> {
+    let backup = backup_path(path);
+    match fs::read_to_string(&backup) {
+        Ok(content) if is_valid_toml(&content) => {
+            write_atomic(path, &content)?;
+            Ok(true)
+        }
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_error(path: &Path, content: &str, source: toml::de::Error) -> Error {
+    let backup = backup_path(path);
+    Error::parse(path, content, source, backup.exists().then_some(backup))
+}
+
 const DEFAULTS_VERSION: u32 = 1;
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Minimum seconds between `last_used` updates of a mapping.
+pub const TOUCH_INTERVAL: u64 = 60;
+
+/// Built-in defaults renamed since version 1, as `(version, old, new)`:
+/// `sync-defaults` renames entries in files older than `version`.
+const RENAMED_DEFAULTS: &[(u32, &str, &str)] = &[];
+
+/// The built-in defaults as shipped in `version`, the base for merging a file
+/// of that version. Keep a frozen copy here when bumping `DEFAULTS_VERSION`.
+fn builtin_defaults(version: u32) -> Option<Vec<PortMapping>> {
+    match version {
+        DEFAULTS_VERSION => Some(DefaultsConfig::create_defaults().ports),
+        _ => None,
+    }
+}
+
+/// The built-in defaults over time, which `sync-defaults` merges against.
+struct DefaultsHistory {
+    /// Current version; `frozen` must know it
+    version: u32,
+    frozen: fn(u32) -> Option<Vec<PortMapping>>,
+    renamed: &'static [(u32, &'static str, &'static str)],
+}
+
+const BUILTIN_HISTORY: DefaultsHistory = DefaultsHistory {
+    version: DEFAULTS_VERSION,
+    frozen: builtin_defaults,
+    renamed: RENAMED_DEFAULTS,
+};
+
+pub(crate) const DEFAULTS_FILE: &str = "defaults.toml";
+pub(crate) const CONFIG_FILE: &str = "config.toml";
+
+/// Overrides the config directory; `--config-dir` sets it for the process.
+pub const CONFIG_DIR_ENV: &str = "PORTS_MANAGER_CONFIG_DIR";
+/// Overrides the system-wide config directory.
+pub const SYSTEM_DIR_ENV: &str = "PORTS_MANAGER_SYSTEM_DIR";
+
+#[cfg(unix)]
+const SYSTEM_DIR: Option<&str> = Some("/etc/ports-manager");
+#[cfg(not(unix))]
+const SYSTEM_DIR: Option<&str> = None;
+
+/// The config directory from an explicit override, `XDG_CONFIG_HOME` (only
+/// if absolute, as the spec requires) or the home directory.
+fn resolve_config_dir(
+    explicit: Option<OsString>,
+    xdg_config_home: Option<OsString>,
+    home_dir: Option<PathBuf>,
+) -> Option<PathBuf> {
+    if let Some(dir) = explicit.filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = xdg_config_home
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+    {
+        return Some(dir.join("ports-manager"));
+    }
+    Some(home_dir?.join(".config").join("ports-manager"))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct DefaultsConfig {
     #[serde(default = "default_version")]
     pub version: u32,
     #[serde(default)]
     pub ports: Vec<PortMapping>,
+    /// File these defaults were loaded from; the default location if `None`.
+    #[serde(skip)]
+    path: Option<PathBuf>,
 }
 
 fn default_version() -> u32 {
@@ -195,223 +1284,291 @@ fn default_version() -> u32 {
 }
 
 impl DefaultsConfig {
-    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+    /// Loads defaults.toml from its default location. With `lock` held, it is
+    /// created from the built-ins on first use and upgraded in place; see
+    /// [`Config::load`]. System-wide defaults are used as they are and not copied.
+    pub fn load(lock: Option<&ConfigLock>) -> Result<Self> {
         let defaults_path = Self::defaults_path()?;
+        let (defaults, schema) = Self::read(&defaults_path)?;
+        let upgrade = match schema {
+            Some(schema) => schema < DEFAULTS_SCHEMA,
+            None => Self::system_path().map_or(true, |p| !p.exists()),
+        };
+        if lock.is_some() && upgrade {
+            defaults.save()?;
+        }
+        Ok(defaults)
+    }
+
+    /// Loads defaults from an explicit path, falling back to the system-wide
+    /// defaults.toml and then the built-in defaults if the file doesn't exist.
+    pub fn load_from(path: &Path) -> Result<Self> {
+        Self::read(path).map(|(defaults, _)| defaults)
+    }
+
+    /// Reads and migrates a defaults file, also returning the schema version
+    /// it was written with (`None` if it doesn't exist).
+    fn read(path: &Path) -> Result<(Self, Option<u32>)> {
+        let (mut defaults, schema): (Self, _) = match fs::read_to_string(path) {
+            Ok(content) => {
+                let (defaults, schema) =
+                    parse_versioned(path, &content, DEFAULTS_SCHEMA, DEFAULTS_MIGRATIONS)?;
+                (defaults, Some(schema))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Self::load_system()?, None),
+            Err(e) => return Err(Error::io(path, e)),
+        };
+        defaults.path = Some(path.to_path_buf());
+        Ok((defaults, schema))
+    }
 
-        if !defaults_path.exists() {
-            // Create default defaults if they don't exist
-            let default_config = Self::create_defaults();
-            default_config.save()?;
-            return Ok(default_config);
+    /// The system-wide defaults, or the built-ins if there are none.
+    fn load_system() -> Result<Self> {
+        let Some(path) = Self::system_path() else {
+            return Ok(Self::create_defaults());
+        };
+        match fs::read_to_string(&path) {
+            Ok(content) => parse_versioned(&path, &content, DEFAULTS_SCHEMA, DEFAULTS_MIGRATIONS)
+                .map(|(defaults, _)| defaults),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::create_defaults()),
+            Err(e) => Err(Error::io(&path, e)),
         }
+    }
 
-        let content = fs::read_to_string(&defaults_path)?;
-        let config: DefaultsConfig = toml::from_str(&content)?;
-        Ok(config)
+    /// Location of the read-only system-wide defaults.toml.
+    pub fn system_path() -> Option<PathBuf> {
+        Config::system_dir().map(|dir| dir.join(DEFAULTS_FILE))
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let defaults_path = Self::defaults_path()?;
+    /// Writes the defaults back to the file they were loaded from.
+    pub fn save(&self) -> Result<()> {
+        let defaults_path = match &self.path {
+            Some(path) => path.clone(),
+            None => Self::defaults_path()?,
+        };
 
         // Create config directory if it doesn't exist
         if let Some(parent) = defaults_path.parent() {
-            fs::create_dir_all(parent)?;
+            fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
         }
 
-        let content = toml::to_string_pretty(self)?;
-        fs::write(&defaults_path, content)?;
-        Ok(())
+        let content = versioned(DEFAULTS_SCHEMA, toml::to_string_pretty(self)?);
+        write_atomic(&defaults_path, &content).map_err(|e| Error::io(&defaults_path, e))
     }
 
-    fn defaults_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
-        Ok(Config::config_dir()?.join("defaults.toml"))
+    /// Default location of defaults.toml.
+    pub fn defaults_path() -> Result<PathBuf> {
+        Ok(Config::config_dir()?.join(DEFAULTS_FILE))
     }
 
-    pub fn reset() -> Result<(), Box<dyn std::error::Error>> {
+    pub fn reset() -> Result<()> {
         let defaults = Self::create_defaults();
         defaults.save()
     }
 
-    pub fn sync() -> Result<isize, Box<dyn std::error::Error>> {
-        let defaults_path = Self::defaults_path()?;
-        let built_in_defaults = Self::create_defaults();
-
-        let old_count = if defaults_path.exists() {
-            Self::load().map(|d| d.ports.len()).unwrap_or(0)
-        } else {
-            0
-        };
-
-        // Just replace with new defaults
-        built_in_defaults.save()?;
+    /// Brings defaults.toml up to the current built-in defaults with a
+    /// three-way merge (see [`sync::merge`]), keeping local edits. With
+    /// `dry_run` nothing is written. Returns what changed.
+    pub fn sync(dry_run: bool) -> Result<Vec<sync::Change>> {
+        Self::sync_with(&Self::defaults_path()?, dry_run, &BUILTIN_HISTORY)
+    }
 
-        let new_count = built_in_defaults.ports.len();
-        Ok(new_count as isize - old_count as isize)
+    /// [`DefaultsConfig::sync`] for the file at `defaults_path`, against `history`.
+    fn sync_with(
+        defaults_path: &Path,
+        dry_run: bool,
+        history: &DefaultsHistory,
+    ) -> Result<Vec<sync::Change>> {
+        let defaults_path = defaults_path.to_path_buf();
+        let local = Self::load_from(&defaults_path)?;
+        let latest = (history.frozen)(history.version).unwrap_or_default();
+
+        // Without the built-ins the file came from, treat every entry as a local
+        // edit or addition and every missing built-in as new
+        let base = (history.frozen)(local.version).unwrap_or_default();
+        let renames: Vec<(&str, &str)> = history
+            .renamed
+            .iter()
+            .filter(|(version, _, _)| *version > local.version)
+            .map(|(_, old, new)| (*old, *new))
+            .collect();
+        let merged = sync::merge(&base, &latest, &local.ports, &renames);
+
+        let stale = !defaults_path.exists() || local.version != history.version;
+        if !dry_run && (stale || !merged.changes.is_empty()) {
+            let defaults = DefaultsConfig {
+                version: history.version,
+                ports: merged.ports,
+                path: Some(defaults_path),
+            };
+            defaults.save()?;
+        }
+        Ok(merged.changes)
     }
 
     fn create_defaults() -> Self {
         let mut config = DefaultsConfig::default();
 
         // Databases
-        config.ports.push(PortMapping {
-            name: "postgres".to_string(),
-            port: PortSpec::Single(5432),
-            description: Some("PostgreSQL database".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "mysql".to_string(),
-            port: PortSpec::Single(3306),
-            description: Some("MySQL database".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "mongodb".to_string(),
-            port: PortSpec::Single(27017),
-            description: Some("MongoDB database".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "redis".to_string(),
-            port: PortSpec::Single(6379),
-            description: Some("Redis cache".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "elasticsearch".to_string(),
-            port: PortSpec::Single(9200),
-            description: Some("Elasticsearch search engine".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "cassandra".to_string(),
-            port: PortSpec::Single(9042),
-            description: Some("Apache Cassandra database".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "couchdb".to_string(),
-            port: PortSpec::Single(5984),
-            description: Some("Apache CouchDB database".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "influxdb".to_string(),
-            port: PortSpec::Single(8086),
-            description: Some("InfluxDB time-series database".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "neo4j".to_string(),
-            port: PortSpec::Single(7687),
-            description: Some("Neo4j graph database".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "clickhouse".to_string(),
-            port: PortSpec::Single(9000),
-            description: Some("ClickHouse analytics database".to_string()),
-        });
+        config.ports.push(PortMapping::new(
+            "postgres",
+            PortSpec::Single(5432),
+            Some("PostgreSQL database"),
+        ));
+        config.ports.push(PortMapping::new(
+            "mysql",
+            PortSpec::Single(3306),
+            Some("MySQL database"),
+        ));
+        config.ports.push(PortMapping::new(
+            "mongodb",
+            PortSpec::Single(27017),
+            Some("MongoDB database"),
+        ));
+        config.ports.push(PortMapping::new(
+            "redis",
+            PortSpec::Single(6379),
+            Some("Redis cache"),
+        ));
+        config.ports.push(PortMapping::new(
+            "elasticsearch",
+            PortSpec::Single(9200),
+            Some("Elasticsearch search engine"),
+        ));
+        config.ports.push(PortMapping::new(
+            "cassandra",
+            PortSpec::Single(9042),
+            Some("Apache Cassandra database"),
+        ));
+        config.ports.push(PortMapping::new(
+            "couchdb",
+            PortSpec::Single(5984),
+            Some("Apache CouchDB database"),
+        ));
+        config.ports.push(PortMapping::new(
+            "influxdb",
+            PortSpec::Single(8086),
+            Some("InfluxDB time-series database"),
+        ));
+        config.ports.push(PortMapping::new(
+            "neo4j",
+            PortSpec::Single(7687),
+            Some("Neo4j graph database"),
+        ));
+        config.ports.push(PortMapping::new(
+            "clickhouse",
+            PortSpec::Single(9000),
+            Some("ClickHouse analytics database"),
+        ));
 
         // Message Brokers
-        config.ports.push(PortMapping {
-            name: "rabbitmq".to_string(),
-            port: PortSpec::Single(5672),
-            description: Some("RabbitMQ message broker".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "kafka".to_string(),
-            port: PortSpec::Single(9092),
-            description: Some("Apache Kafka message broker".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "zookeeper".to_string(),
-            port: PortSpec::Single(2181),
-            description: Some("Apache ZooKeeper coordination service".to_string()),
-        });
+        config.ports.push(PortMapping::new(
+            "rabbitmq",
+            PortSpec::Single(5672),
+            Some("RabbitMQ message broker"),
+        ));
+        config.ports.push(PortMapping::new(
+            "kafka",
+            PortSpec::Single(9092),
+            Some("Apache Kafka message broker"),
+        ));
+        config.ports.push(PortMapping::new(
+            "zookeeper",
+            PortSpec::Single(2181),
+            Some("Apache ZooKeeper coordination service"),
+        ));
 
         // Caching
-        config.ports.push(PortMapping {
-            name: "memcached".to_string(),
-            port: PortSpec::Single(11211),
-            description: Some("Memcached cache".to_string()),
-        });
+        config.ports.push(PortMapping::new(
+            "memcached",
+            PortSpec::Single(11211),
+            Some("Memcached cache"),
+        ));
 
         // Monitoring
-        config.ports.push(PortMapping {
-            name: "prometheus".to_string(),
-            port: PortSpec::Single(9090),
-            description: Some("Prometheus monitoring".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "grafana".to_string(),
-            port: PortSpec::Single(3000),
-            description: Some("Grafana dashboard".to_string()),
-        });
+        config.ports.push(PortMapping::new(
+            "prometheus",
+            PortSpec::Single(9090),
+            Some("Prometheus monitoring"),
+        ));
+        config.ports.push(PortMapping::new(
+            "grafana",
+            PortSpec::Single(3000),
+            Some("Grafana dashboard"),
+        ));
 
         // Infrastructure
-        config.ports.push(PortMapping {
-            name: "docker".to_string(),
-            port: PortSpec::Single(2375),
-            description: Some("Docker daemon".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "etcd".to_string(),
-            port: PortSpec::Single(2379),
-            description: Some("etcd distributed key-value store".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "consul".to_string(),
-            port: PortSpec::Single(8500),
-            description: Some("Consul service mesh".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "vault".to_string(),
-            port: PortSpec::Single(8200),
-            description: Some("HashiCorp Vault secrets management".to_string()),
-        });
+        config.ports.push(PortMapping::new(
+            "docker",
+            PortSpec::Single(2375),
+            Some("Docker daemon"),
+        ));
+        config.ports.push(PortMapping::new(
+            "etcd",
+            PortSpec::Single(2379),
+            Some("etcd distributed key-value store"),
+        ));
+        config.ports.push(PortMapping::new(
+            "consul",
+            PortSpec::Single(8500),
+            Some("Consul service mesh"),
+        ));
+        config.ports.push(PortMapping::new(
+            "vault",
+            PortSpec::Single(8200),
+            Some("HashiCorp Vault secrets management"),
+        ));
 
         // CI/CD
-        config.ports.push(PortMapping {
-            name: "jenkins".to_string(),
-            port: PortSpec::Single(8080),
-            description: Some("Jenkins CI/CD".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "sonarqube".to_string(),
-            port: PortSpec::Single(9000),
-            description: Some("SonarQube code quality".to_string()),
-        });
-
-        // Storage
-        config.ports.push(PortMapping {
-            name: "minio".to_string(),
-            port: PortSpec::Single(9000),
-            description: Some("MinIO object storage".to_string()),
-        });
-
-        // Network Services
-        config.ports.push(PortMapping {
-            name: "http".to_string(),
-            port: PortSpec::Single(80),
-            description: Some("HTTP web server".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "https".to_string(),
-            port: PortSpec::Single(443),
-            description: Some("HTTPS web server".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "ssh".to_string(),
-            port: PortSpec::Single(22),
-            description: Some("SSH server".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "ftp".to_string(),
-            port: PortSpec::Single(21),
-            description: Some("FTP server".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "smtp".to_string(),
-            port: PortSpec::Single(25),
-            description: Some("SMTP mail server".to_string()),
-        });
-        config.ports.push(PortMapping {
-            name: "dns".to_string(),
-            port: PortSpec::Single(53),
-            description: Some("DNS server".to_string()),
-        });
+        config.ports.push(PortMapping::new(
+            "jenkins",
+            PortSpec::Single(8080),
+            Some("Jenkins CI/CD"),
+        ));
+        config.ports.push(PortMapping::new(
+            "sonarqube",
+            PortSpec::Single(9000),
+            Some("SonarQube code quality"),
+        ));
+
+        // Storage
+        config.ports.push(PortMapping::new(
+            "minio",
+            PortSpec::Single(9000),
+            Some("MinIO object storage"),
+        ));
+
+        // Network Services
+        config.ports.push(PortMapping::new(
+            "http",
+            PortSpec::Single(80),
+            Some("HTTP web server"),
+        ));
+        config.ports.push(PortMapping::new(
+            "https",
+            PortSpec::Single(443),
+            Some("HTTPS web server"),
+        ));
+        config.ports.push(PortMapping::new(
+            "ssh",
+            PortSpec::Single(22),
+            Some("SSH server"),
+        ));
+        config.ports.push(PortMapping::new(
+            "ftp",
+            PortSpec::Single(21),
+            Some("FTP server"),
+        ));
+        config.ports.push(PortMapping::new(
+            "smtp",
+            PortSpec::Single(25),
+            Some("SMTP mail server"),
+        ));
+        config.ports.push(PortMapping::new(
+            "dns",
+            PortSpec::Single(53),
+            Some("DNS server"),
+        ));
 
         config
     }
@@ -420,6 +1577,7 @@ impl DefaultsConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
 
     #[test]
     fn test_portspec_parse_single_port() {
@@ -632,6 +1790,318 @@ mod tests {
         assert!(found.is_none());
     }
 
+    #[test]
+    fn test_config_resolve_user_source() {
+        let mut config = Config::default();
+        config.add_port("test".to_string(), PortSpec::Single(8080), None);
+
+        let (mapping, source) = config.resolve("test").unwrap();
+        assert_eq!(mapping.name, "test");
+        assert_eq!(source, MappingSource::User);
+    }
+
+    #[test]
+    fn test_config_resolve_in_project_scope() {
+        let mut config = Config::default();
+        config.add_port("api".to_string(), PortSpec::Single(8080), None);
+        config.scope = Some("shop".to_string());
+        config.add_port("api".to_string(), PortSpec::Single(8081), None);
+
+        let (mapping, _) = config.resolve("api").unwrap();
+        assert!(matches!(mapping.port, PortSpec::Single(8081)));
+        assert_eq!(mapping.qualified_name(), "shop/api");
+        assert_eq!(config.visible_ports().len(), 1);
+
+        // Other projects and the global scope fall back to the unscoped entry
+        config.scope = Some("blog".to_string());
+        let (mapping, _) = config.resolve("api").unwrap();
+        assert!(matches!(mapping.port, PortSpec::Single(8080)));
+        config.scope = None;
+        let (mapping, _) = config.resolve("api").unwrap();
+        assert!(matches!(mapping.port, PortSpec::Single(8080)));
+    }
+
+    #[test]
+    fn test_config_project_port_not_visible_globally() {
+        let mut config = Config {
+            scope: Some("shop".to_string()),
+            ..Default::default()
+        };
+        config.add_port(
+            "only-in-shop-xyz123".to_string(),
+            PortSpec::Single(8081),
+            None,
+        );
+        assert_eq!(config.visible_ports().len(), 1);
+
+        config.scope = None;
+        assert!(config.resolve("only-in-shop-xyz123").is_none());
+        assert_eq!(config.visible_ports().len(), 0);
+        assert_eq!(config.list_ports().len(), 1);
+    }
+
+    #[test]
+    fn test_config_remove_port_in_project_scope() {
+        let mut config = Config::default();
+        config.add_port("api".to_string(), PortSpec::Single(8080), None);
+        config.scope = Some("shop".to_string());
+        config.add_port("api".to_string(), PortSpec::Single(8081), None);
+
+        assert!(config.remove_port("api"));
+        assert_eq!(config.ports.len(), 1);
+        assert!(config.ports[0].project.is_none());
+    }
+
+    fn project_config() -> Config {
+        let file: ProjectFile =
+            toml::from_str("[services]\nweb = 3000\nredis = \"auto\"\ndocs = \"4000\"\n").unwrap();
+        Config {
+            scope: Some("shop".to_string()),
+            project_file: Some(file),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_config_resolve_project_file_fixed() {
+        let mut config = project_config();
+        config.scope = None;
+        config.add_port("web".to_string(), PortSpec::Single(8080), None);
+        config.scope = Some("shop".to_string());
+
+        // The project file beats global mappings
+        let (mapping, source) = config.resolve("web").unwrap();
+        assert!(matches!(mapping.port, PortSpec::Single(3000)));
+        assert_eq!(source, MappingSource::Project);
+        assert_eq!(mapping.qualified_name(), "shop/web");
+
+        // ...but a scoped user mapping beats the project file
+        config.add_port("web".to_string(), PortSpec::Single(3001), None);
+        let (mapping, source) = config.resolve("web").unwrap();
+        assert!(matches!(mapping.port, PortSpec::Single(3001)));
+        assert_eq!(source, MappingSource::User);
+    }
+
+    #[test]
+    fn test_config_resolve_project_file_auto() {
+        let mut config = project_config();
+
+        // An unresolved auto placeholder must not fall through to defaults
+        assert!(config.resolve("redis").is_none());
+
+        config.add_port("redis".to_string(), PortSpec::Single(8123), None);
+        let (mapping, source) = config.resolve("redis").unwrap();
+        assert!(matches!(mapping.port, PortSpec::Single(8123)));
+        assert_eq!(source, MappingSource::User);
+    }
+
+    #[test]
+    fn test_config_project_file_ports() {
+        let mut config = project_config();
+        assert_eq!(config.project_file_ports().len(), 2);
+        assert!(config.get_used_ports().contains(&3000));
+        assert!(config.get_used_ports().contains(&4000));
+
+        config.add_port("docs".to_string(), PortSpec::Single(4001), None);
+        let ports = config.project_file_ports();
+        assert_eq!(ports.len(), 1);
+        assert_eq!(ports[0].name, "web");
+    }
+
+    fn pool_config() -> Config {
+        let mut config = Config::default();
+        config.add_port(
+            "pool-xyz123".to_string(),
+            PortSpec::Range {
+                start: 61000,
+                end: 61100,
+            },
+            None,
+        );
+        config
+    }
+
+    #[test]
+    fn test_config_pool_of() {
+        let config = pool_config();
+        assert!(config.pool_of("pool-xyz123/worker-1").is_some());
+        assert!(config.pool_of("pool-xyz123").is_none());
+        assert!(config.pool_of("pool-xyz123/").is_none());
+        assert!(config.pool_of("not-a-pool-xyz123/worker-1").is_none());
+    }
+
+    #[test]
+    fn test_config_lease_from_pool() {
+        let mut config = pool_config();
+
+        let first = config.lease_from_pool("pool-xyz123/a").unwrap().unwrap();
+        let second = config.lease_from_pool("pool-xyz123/b").unwrap().unwrap();
+        let (PortSpec::Single(first_port), PortSpec::Single(second_port)) =
+            (first.port, second.port)
+        else {
+            panic!("Expected single ports");
+        };
+        assert_ne!(first_port, second_port);
+        assert!((61000..=61100).contains(&first_port));
+        assert!((61000..=61100).contains(&second_port));
+        let pool = config.find_port("pool-xyz123").unwrap();
+        assert_eq!(config.pool_leases(&pool).len(), 2);
+
+        // The lease is found by name from now on
+        assert!(config.find_port("pool-xyz123/a").is_some());
+
+        // Releasing returns the port to the pool
+        assert!(config.remove_port("pool-xyz123/a"));
+        let again = config.lease_from_pool("pool-xyz123/c").unwrap().unwrap();
+        assert!(matches!(again.port, PortSpec::Single(p) if p == first_port));
+    }
+
+    #[test]
+    fn test_config_lease_from_exhausted_pool() {
+        let mut config = Config::default();
+        config.add_port(
+            "tiny-xyz123".to_string(),
+            PortSpec::Range {
+                start: 61200,
+                end: 61201,
+            },
+            None,
+        );
+        config.add_port("x".to_string(), PortSpec::Single(61200), None);
+        config.add_port("y".to_string(), PortSpec::Single(61201), None);
+
+        let err = config.lease_from_pool("tiny-xyz123/a").unwrap_err();
+        assert!(err.to_string().contains("No free port left"));
+    }
+
+    #[test]
+    fn test_config_lease_skips_blocks_and_other_layers() {
+        let mut config = Config::default();
+        config.add_port(
+            "tiny-xyz123".to_string(),
+            PortSpec::Range {
+                start: 61300,
+                end: 61304,
+            },
+            None,
+        );
+        // A block from `get --block` inside the pool, and a system port
+        config.add_port(
+            "blk".to_string(),
+            PortSpec::Range {
+                start: 61300,
+                end: 61302,
+            },
+            None,
+        );
+        config
+            .system
+            .push(PortMapping::new("sys", PortSpec::Single(61303), None));
+
+        let lease = config.lease_from_pool("tiny-xyz123/a").unwrap().unwrap();
+        assert_eq!(lease.port, PortSpec::Single(61304));
+        assert!(config.lease_from_pool("tiny-xyz123/b").is_err());
+    }
+
+    #[test]
+    fn test_config_lease_inherits_pool_protocol() {
+        let mut config = pool_config();
+        config.ports[0].protocol = Protocol::Udp;
+
+        let lease = config.lease_from_pool("pool-xyz123/dns").unwrap().unwrap();
+        assert_eq!(lease.protocol, Protocol::Udp);
+    }
+
+    #[test]
+    fn test_port_mapping_protocol_serialization() {
+        let mut config = Config::default();
+        config.add_port("web".to_string(), PortSpec::Single(8080), None);
+        config
+            .add_port("dns".to_string(), PortSpec::Single(5353), None)
+            .protocol = Protocol::Udp;
+
+        let content = toml::to_string_pretty(&config).unwrap();
+        // TCP is the default and stays out of the file
+        assert_eq!(content.matches("protocol").count(), 1);
+        assert!(content.contains("protocol = \"udp\""));
+
+        let parsed: Config = toml::from_str(&content).unwrap();
+        assert_eq!(parsed.ports[0].protocol, Protocol::Tcp);
+        assert_eq!(parsed.ports[1].protocol, Protocol::Udp);
+    }
+
+    #[test]
+    fn test_config_lease_non_pool_name() {
+        let mut config = Config::default();
+        assert!(config
+            .lease_from_pool("no-such-pool-xyz123/a")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_allocation_config_parse() {
+        let config: Config = toml::from_str(
+            "[allocation]\nstart = 20000\nend = 30000\npolicy = \"hash\"\nexclude = [20001, { start = 21000, end = 21002 }]\n",
+        )
+        .unwrap();
+        assert_eq!(config.allocation.start, 20000);
+        assert_eq!(config.allocation.end, 30000);
+        assert_eq!(config.allocation.policy, Policy::Hash);
+        assert_eq!(
+            config.allocation.excluded_ports(),
+            vec![20001, 21000, 21001, 21002]
+        );
+    }
+
+    #[test]
+    fn test_allocation_config_defaults_not_serialized() {
+        let config = Config::default();
+        let content = toml::to_string_pretty(&config).unwrap();
+        assert!(!content.contains("allocation"));
+
+        let parsed: Config = toml::from_str("[allocation]\npolicy = \"random\"\n").unwrap();
+        assert_eq!(parsed.allocation.start, 8000);
+        assert_eq!(parsed.allocation.end, 65535);
+        assert!(toml::to_string_pretty(&parsed)
+            .unwrap()
+            .contains("[allocation]"));
+    }
+
+    #[test]
+    fn test_config_find_free_port_respects_settings() {
+        let mut config = Config::default();
+        config.add_port("taken".to_string(), PortSpec::Single(62000), None);
+        let settings = AllocationConfig {
+            start: 62000,
+            end: 62010,
+            exclude: vec![PortSpec::Single(62001)],
+            ..Default::default()
+        };
+
+        let port = config
+            .find_free_port("svc", &settings, Protocol::Tcp)
+            .unwrap();
+        assert!((62002..=62010).contains(&port));
+
+        let block = config.find_free_block(3, &settings, Protocol::Tcp).unwrap();
+        assert!(block >= 62002 && block + 2 <= 62010);
+    }
+
+    #[test]
+    fn test_config_effective_defaults() {
+        let mut config = Config::default();
+        config.add_port("redis".to_string(), PortSpec::Single(7000), None);
+        config.ignored_defaults.push("mysql".to_string());
+
+        let defaults = DefaultsConfig::create_defaults();
+        let effective = config.effective_defaults(&defaults);
+        assert!(effective.iter().any(|m| m.name == "postgres"));
+        assert!(!effective.iter().any(|m| m.name == "mysql"));
+        assert!(!effective.iter().any(|m| m.name == "redis"));
+        assert_eq!(effective.len(), defaults.ports.len() - 2);
+    }
+
     #[test]
     fn test_config_list_ports_empty() {
         let config = Config::default();
@@ -743,6 +2213,622 @@ mod tests {
         assert_eq!(config.ports.len(), 0);
     }
 
+    #[test]
+    fn test_config_upsert_port() {
+        let mut config = Config::default();
+        let (previous, _) = config.upsert_port("web", PortSpec::Single(8080));
+        assert!(previous.is_none());
+
+        let (previous, mapping) = config.upsert_port("web", PortSpec::Single(8081));
+        mapping.description = Some("Web".to_string());
+        assert!(matches!(previous.unwrap().port, PortSpec::Single(8080)));
+        assert_eq!(config.ports.len(), 1);
+        assert_eq!(
+            config.find_port("web").unwrap().port,
+            PortSpec::Single(8081)
+        );
+
+        // Other scopes are left alone
+        config.scope = Some("shop".to_string());
+        let (previous, _) = config.upsert_port("web", PortSpec::Single(9000));
+        assert!(previous.is_none());
+        assert_eq!(config.ports.len(), 2);
+    }
+
+    #[test]
+    fn test_config_upsert_port_collapses_duplicates() {
+        let mut config = Config::default();
+        config.add_port("test".to_string(), PortSpec::Single(8080), None);
+        config.add_port("other".to_string(), PortSpec::Single(9000), None);
+        config.add_port("test".to_string(), PortSpec::Single(8081), None);
+
+        config.upsert_port("test", PortSpec::Single(8082));
+        let names: Vec<&str> = config.ports.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["test", "other"]);
+        assert_eq!(config.ports[0].port, PortSpec::Single(8082));
+    }
+
+    #[test]
+    fn test_config_conflicts() {
+        let mut config = pool_config();
+        config.add_port("web".to_string(), PortSpec::Single(8080), None);
+        let defaults = DefaultsConfig {
+            version: DEFAULTS_VERSION,
+            ports: vec![PortMapping::new("redis", PortSpec::Single(6379), None)],
+            ..Default::default()
+        };
+
+        let candidate = PortMapping::new("api", PortSpec::Single(8080), None);
+        let conflicts = config.conflicts(&candidate, &defaults);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0.name, "web");
+        assert_eq!(conflicts[0].1, MappingSource::User);
+
+        let candidate = PortMapping::new("cache", PortSpec::Single(6379), None);
+        let conflicts = config.conflicts(&candidate, &defaults);
+        assert_eq!(conflicts[0].1, MappingSource::Default);
+
+        // Its own entry, pool leases and shared ports are fine
+        let candidate = PortMapping::new("web", PortSpec::Single(8080), None);
+        assert!(config.conflicts(&candidate, &defaults).is_empty());
+        let candidate = PortMapping::new("pool-xyz123/a", PortSpec::Single(61005), None);
+        assert!(config.conflicts(&candidate, &defaults).is_empty());
+        let mut candidate = PortMapping::new("api", PortSpec::Single(8080), None);
+        candidate.shared = true;
+        assert!(config.conflicts(&candidate, &defaults).is_empty());
+    }
+
+    fn temp_config_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ports-manager-config-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Built-ins where version 2 renamed `b` to `c` and added `d`.
+    fn frozen_history(version: u32) -> Option<Vec<PortMapping>> {
+        let mapping = |name: &str, port| PortMapping::new(name, PortSpec::Single(port), None);
+        match version {
+            1 => Some(vec![mapping("a", 41001), mapping("b", 41002)]),
+            2 => Some(vec![
+                mapping("a", 41001),
+                mapping("c", 41002),
+                mapping("d", 41004),
+            ]),
+            _ => None,
+        }
+    }
+
+    const HISTORY: DefaultsHistory = DefaultsHistory {
+        version: 2,
+        frozen: frozen_history,
+        renamed: &[(2, "b", "c")],
+    };
+
+    #[test]
+    fn test_sync_renames_and_adds() {
+        let dir = temp_config_dir("sync-rename");
+        let path = dir.join(DEFAULTS_FILE);
+        fs::write(
+            &path,
+            "version = 1\n[[ports]]\nname = \"a\"\nport = 41001\n\
+             [[ports]]\nname = \"b\"\nport = 41002\n",
+        )
+        .unwrap();
+
+        let preview = DefaultsConfig::sync_with(&path, true, &HISTORY).unwrap();
+        assert_eq!(preview.len(), 2);
+        assert_eq!(DefaultsConfig::load_from(&path).unwrap().version, 1);
+
+        let changes = DefaultsConfig::sync_with(&path, false, &HISTORY).unwrap();
+        assert_eq!(changes, preview);
+        assert!(matches!(&changes[0], sync::Change::Renamed { from, to }
+            if from == "b" && to.name == "c"));
+        assert!(matches!(&changes[1], sync::Change::Added(m) if m.name == "d"));
+        let synced = DefaultsConfig::load_from(&path).unwrap();
+        assert_eq!(synced.version, 2);
+        let names: Vec<_> = synced.ports.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "c", "d"]);
+        assert!(DefaultsConfig::sync_with(&path, false, &HISTORY)
+            .unwrap()
+            .is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sync_unknown_version_adds_missing_builtins() {
+        let dir = temp_config_dir("sync-unknown");
+        let path = dir.join(DEFAULTS_FILE);
+        // No frozen copy of version 7, and an edited port for `a`
+        fs::write(
+            &path,
+            "version = 7\n[[ports]]\nname = \"a\"\nport = 42001\n",
+        )
+        .unwrap();
+
+        let changes = DefaultsConfig::sync_with(&path, false, &HISTORY).unwrap();
+        let added: Vec<_> = changes
+            .iter()
+            .filter_map(|c| match c {
+                sync::Change::Added(m) => Some(m.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(added, vec!["c", "d"]);
+        let synced = DefaultsConfig::load_from(&path).unwrap();
+        assert_eq!(synced.ports[0].port, PortSpec::Single(42001));
+        assert_eq!(synced.ports.len(), 3);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_backup_path() {
+        let path = Path::new("/tmp/ports-manager/config.toml");
+        assert_eq!(
+            backup_path(path),
+            Path::new("/tmp/ports-manager/config.toml.bak")
+        );
+    }
+
+    #[test]
+    fn test_write_atomic_keeps_backup_of_previous_version() {
+        let dir = temp_config_dir("atomic");
+        let path = dir.join("config.toml");
+
+        write_atomic(&path, "ignored_defaults = []\n").unwrap();
+        assert!(!backup_path(&path).exists());
+
+        write_atomic(&path, "ignored_defaults = [\"redis\"]\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "ignored_defaults = [\"redis\"]\n"
+        );
+        assert_eq!(
+            fs::read_to_string(backup_path(&path)).unwrap(),
+            "ignored_defaults = []\n"
+        );
+
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_write_atomic_does_not_back_up_corrupt_file() {
+        let dir = temp_config_dir("corrupt");
+        let path = dir.join("config.toml");
+        fs::write(backup_path(&path), "ignored_defaults = []\n").unwrap();
+        fs::write(&path, "[[ports]\nname = ").unwrap();
+
+        write_atomic(&path, "ignored_defaults = [\"mysql\"]\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(backup_path(&path)).unwrap(),
+            "ignored_defaults = []\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_restore_backup() {
+        let dir = temp_config_dir("restore");
+        let path = dir.join("config.toml");
+        assert!(!restore_backup(&path).unwrap());
+
+        fs::write(backup_path(&path), "ignored_defaults = [\"redis\"]\n").unwrap();
+        fs::write(&path, "ignored_defaults = [").unwrap();
+        assert!(restore_backup(&path).unwrap());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "ignored_defaults = [\"redis\"]\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_restore_backups_only_touches_broken_files() {
+        let dir = temp_config_dir("restore-broken");
+        let config_path = dir.join(CONFIG_FILE);
+        let defaults_path = dir.join(DEFAULTS_FILE);
+        fs::write(
+            backup_path(&config_path),
+            "ignored_defaults = [\"redis\"]\n",
+        )
+        .unwrap();
+        fs::write(&config_path, "ignored_defaults = []\n").unwrap();
+        fs::write(backup_path(&defaults_path), "version = 1\n").unwrap();
+        assert!(Config::restore_backups_in(&dir).unwrap().is_empty());
+
+        fs::write(&defaults_path, "version = [").unwrap();
+        assert_eq!(
+            Config::restore_backups_in(&dir).unwrap(),
+            vec![defaults_path.clone()]
+        );
+        assert_eq!(fs::read_to_string(&defaults_path).unwrap(), "version = 1\n");
+        assert_eq!(
+            fs::read_to_string(&config_path).unwrap(),
+            "ignored_defaults = []\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_error_mentions_backup() {
+        let dir = temp_config_dir("corrupt-error");
+        let path = dir.join("config.toml");
+        let err = toml::from_str::<Config>("ports = 1").unwrap_err();
+
+        let message = parse_error(&path, "ports = 1", err.clone()).to_string();
+        assert!(message.contains("is corrupt"));
+        assert!(!message.contains("restore-backup"));
+
+        fs::write(backup_path(&path), "").unwrap();
+        let message = parse_error(&path, "ports = 1", err).to_string();
+        assert!(message.contains("restore-backup"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_resolve_config_dir() {
+        let home = Some(PathBuf::from("/home/me"));
+        assert_eq!(
+            resolve_config_dir(None, None, home.clone()),
+            Some(PathBuf::from("/home/me/.config/ports-manager"))
+        );
+        assert_eq!(
+            resolve_config_dir(None, Some("/xdg".into()), home.clone()),
+            Some(PathBuf::from("/xdg/ports-manager"))
+        );
+        // Relative XDG_CONFIG_HOME is invalid and ignored
+        assert_eq!(
+            resolve_config_dir(None, Some("xdg".into()), home.clone()),
+            Some(PathBuf::from("/home/me/.config/ports-manager"))
+        );
+        assert_eq!(
+            resolve_config_dir(Some("/ci/ports".into()), Some("/xdg".into()), None),
+            Some(PathBuf::from("/ci/ports"))
+        );
+        assert_eq!(resolve_config_dir(Some("".into()), None, None), None);
+    }
+
+    #[test]
+    fn test_system_ports_layer() {
+        let mut config = Config {
+            system: vec![
+                PortMapping::new("postgres", PortSpec::Single(6432), None),
+                PortMapping::new("ci-db", PortSpec::Single(7000), None),
+            ],
+            ..Default::default()
+        };
+        config.add_port("ci-db".to_string(), PortSpec::Single(7001), None);
+
+        let (mapping, source) = config.resolve("postgres").unwrap();
+        assert_eq!(
+            (mapping.port, source),
+            (PortSpec::Single(6432), MappingSource::System)
+        );
+        let (mapping, source) = config.resolve("ci-db").unwrap();
+        assert_eq!(
+            (mapping.port, source),
+            (PortSpec::Single(7001), MappingSource::User)
+        );
+
+        let system = config.system_ports();
+        assert_eq!(system.len(), 1);
+        assert_eq!(system[0].name, "postgres");
+        assert!(config.get_used_ports().contains(&6432));
+
+        let defaults = DefaultsConfig {
+            ports: vec![PortMapping::new("postgres", PortSpec::Single(5432), None)],
+            ..Default::default()
+        };
+        assert!(config.effective_defaults(&defaults).is_empty());
+
+        let candidate = PortMapping::new("web", PortSpec::Single(6432), None);
+        let conflicts = config.conflicts(&candidate, &defaults);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].1, MappingSource::System);
+    }
+
+    #[test]
+    fn test_schema_versions() {
+        assert_eq!(CONFIG_MIGRATIONS.len(), CONFIG_SCHEMA as usize);
+        assert_eq!(DEFAULTS_MIGRATIONS.len(), DEFAULTS_SCHEMA as usize);
+
+        let dir = temp_config_dir("schema");
+        let path = dir.join("config.toml");
+
+        // Files from before schema versions still load, and are stamped on save
+        fs::write(&path, "[[ports]]\nname = \"web\"\nport = 8080\n").unwrap();
+        let config = Config::load_from(&path).unwrap();
+        assert_eq!(config.ports.len(), 1);
+        config.save().unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.starts_with("schema_version = 1\n"));
+        assert!(!fs::read_to_string(backup_path(&path))
+            .unwrap()
+            .contains("schema_version"));
+
+        fs::write(&path, "schema_version = 2\nports = []\n").unwrap();
+        let err = Config::load_from(&path).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnsupportedSchema {
+                found: 2,
+                supported: 1,
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("upgrade ports-manager"));
+
+        fs::write(&path, "schema_version = \"one\"\n").unwrap();
+        assert!(matches!(
+            Config::load_from(&path),
+            Err(Error::Validation(_))
+        ));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_only_upgrades_under_lock() {
+        let dir = temp_config_dir("upgrade-lock");
+        let path = dir.join(CONFIG_FILE);
+        let unversioned = "[[ports]]\nname = \"web\"\nport = 8080\n";
+        fs::write(&path, unversioned).unwrap();
+
+        // Unlocked readers migrate in memory only
+        let config = Config::load_upgrading(&path, None).unwrap();
+        assert_eq!(config.ports.len(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), unversioned);
+        let missing = dir.join("missing.toml");
+        Config::load_upgrading(&missing, None).unwrap();
+        assert!(!missing.exists());
+
+        let lock =
+            ConfigLock::acquire_at(&dir.join(crate::lock::LOCK_FILE), Duration::ZERO).unwrap();
+        Config::load_upgrading(&path, Some(&lock)).unwrap();
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("schema_version = 1\n"));
+        Config::load_upgrading(&missing, Some(&lock)).unwrap();
+        assert!(missing.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_versioned_runs_migrations_in_order() {
+        fn rename_ignored(table: &mut toml::Table) {
+            if let Some(value) = table.remove("ignore") {
+                table.insert("ignored_defaults".to_string(), value);
+            }
+        }
+        fn add_postgres(table: &mut toml::Table) {
+            if let Some(toml::Value::Array(ignored)) = table.get_mut("ignored_defaults") {
+                ignored.push("postgres".into());
+            }
+        }
+        let migrations: &[Migration] = &[from_unversioned, rename_ignored, add_postgres];
+        let path = Path::new("config.toml");
+
+        let (config, found): (Config, u32) = parse_versioned(
+            path,
+            "schema_version = 1\nignore = [\"redis\"]\n",
+            3,
+            migrations,
+        )
+        .unwrap();
+        assert_eq!(found, 1);
+        assert_eq!(config.ignored_defaults, vec!["redis", "postgres"]);
+
+        // Current files skip the chain
+        let (config, found): (Config, u32) = parse_versioned(
+            path,
+            "schema_version = 3\nignore = [\"redis\"]\n",
+            3,
+            migrations,
+        )
+        .unwrap();
+        assert_eq!(found, 3);
+        assert!(config.ignored_defaults.is_empty());
+    }
+
+    #[test]
+    fn test_auto_assigned_mappings_expire() {
+        let mut config = Config::default();
+        let settings = AllocationConfig {
+            start: 41000,
+            end: 41999,
+            ..Default::default()
+        };
+        let (assigned, _) = config
+            .allocate("branch-a", &settings, Protocol::Tcp)
+            .unwrap();
+        let created = assigned
+            .created
+            .expect("auto-assigned mappings are stamped");
+        assert_eq!(assigned.last_used, Some(created));
+        config.add_port("pinned".to_string(), PortSpec::Single(42000), None);
+
+        // Uses inside the interval don't rewrite the file; a TTL always sticks
+        assert!(!config.touch("branch-a", None, created + 1));
+        assert!(config.touch("branch-a", Some(3600), created + 1));
+        assert!(!config.touch("branch-a", Some(3600), created + 2));
+        assert!(config.touch("branch-a", None, created + TOUCH_INTERVAL));
+        assert!(!config.touch("pinned", Some(3600), created + TOUCH_INTERVAL));
+        let pinned = config.unstamped_ports();
+        assert_eq!(pinned.len(), 1);
+        assert_eq!(pinned[0].name, "pinned");
+
+        let last_used = created + TOUCH_INTERVAL;
+        assert!(config.stale_ports(last_used + 3600, None).is_empty());
+        let stale = config.stale_ports(last_used + 3601, None);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].name, "branch-a");
+        // Without a TTL only --unused-for applies, and never to pinned mappings
+        config.ports[0].ttl = None;
+        assert!(config.stale_ports(last_used + 86400, None).is_empty());
+        assert_eq!(config.stale_ports(last_used + 86400, Some(3600)).len(), 1);
+
+        assert!(config.remove_mapping(&config.ports[0].clone()));
+        assert_eq!(config.ports.len(), 1);
+        assert_eq!(config.ports[0].name, "pinned");
+    }
+
+    #[test]
+    fn test_touch_with_corrupt_timestamp() {
+        let mut config = Config::default();
+        config
+            .allocate("web", &AllocationConfig::default(), Protocol::Tcp)
+            .unwrap();
+        config.ports[0].last_used = Some(u64::MAX - 1);
+        assert!(!config.touch("web", None, expiry::now()));
+        assert!(config.touch("web", None, u64::MAX));
+    }
+
+    #[test]
+    fn test_upsert_pins_auto_assigned_mapping() {
+        let mut config = Config::default();
+        config
+            .allocate("web", &AllocationConfig::default(), Protocol::Tcp)
+            .unwrap();
+        config.touch("web", Some(60), expiry::now());
+
+        let (previous, mapping) = config.upsert_port("web", PortSpec::Single(8080));
+        assert!(previous.unwrap().is_auto());
+        assert!(!mapping.is_auto());
+        assert_eq!(mapping.ttl, None);
+        assert!(config.stale_ports(u64::MAX, Some(0)).is_empty());
+    }
+
+    #[test]
+    fn test_bind_and_release_orphans() {
+        let mut config = Config::default();
+        config
+            .allocate("test-1", &AllocationConfig::default(), Protocol::Tcp)
+            .unwrap();
+        config.add_port("pinned".to_string(), PortSpec::Single(42000), None);
+
+        // This process, but started at a different time: a reused PID
+        let exited = Owner {
+            pid: std::process::id(),
+            start_time: 0,
+        };
+        assert!(config.bind("test-1", exited).unwrap());
+        assert!(!config.bind("test-1", exited).unwrap());
+        assert!(config.bind("pinned", exited).is_err());
+        assert!(config.bind("missing", exited).is_err());
+
+        if crate::process::SUPPORTED {
+            let released = config.release_orphans();
+            assert_eq!(released.len(), 1);
+            assert_eq!(released[0].name, "test-1");
+            assert_eq!(config.ports.len(), 1);
+            assert!(config.orphans().is_empty());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bind_refuses_live_owner() {
+        let mut config = Config::default();
+        config
+            .allocate("db", &AllocationConfig::default(), Protocol::Tcp)
+            .unwrap();
+        let mut child = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let first = Owner::of(std::process::id()).unwrap();
+        let second = Owner::of(child.id()).unwrap();
+
+        assert!(config.bind("db", first).unwrap());
+        assert!(!config.bind("db", first).unwrap());
+        assert!(matches!(
+            config.bind("db", second),
+            Err(Error::Validation(_))
+        ));
+        assert_eq!(config.ports[0].owner, Some(first));
+
+        // Once the owner has exited the mapping can be taken over
+        let exited = Owner {
+            start_time: 0,
+            ..first
+        };
+        config.ports[0].owner = Some(exited);
+        assert!(config.bind("db", second).unwrap());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_load_from_explicit_path() {
+        let dir = temp_config_dir("load-from");
+        let path = dir.join("config.toml");
+
+        // A missing file is an empty config and isn't created until saved
+        let mut config = Config::load_from(&path).unwrap();
+        assert!(config.ports.is_empty());
+        assert!(!path.exists());
+        assert!(!config.load_defaults().unwrap().ports.is_empty());
+
+        config.add_port("web".to_string(), PortSpec::Single(8080), None);
+        config.save().unwrap();
+        let reloaded = Config::load_from(&path).unwrap();
+        assert_eq!(reloaded.ports.len(), 1);
+        assert_eq!(reloaded.file_path().unwrap(), path);
+
+        // Defaults come from the same directory
+        fs::write(
+            dir.join(DEFAULTS_FILE),
+            "version = 1\n[[ports]]\nname = \"custom\"\nport = 1234\n",
+        )
+        .unwrap();
+        assert_eq!(
+            reloaded.find_port("custom").unwrap().port,
+            PortSpec::Single(1234)
+        );
+
+        fs::write(&path, "ports = 1").unwrap();
+        assert!(matches!(Config::load_from(&path), Err(Error::Parse { .. })));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_allocate_and_release() {
+        let dir = temp_config_dir("allocate");
+        fs::write(dir.join(DEFAULTS_FILE), "version = 1\nports = []\n").unwrap();
+        let mut config = Config::load_from(&dir.join("config.toml")).unwrap();
+        let settings = AllocationConfig {
+            start: 62100,
+            end: 62200,
+            ..Default::default()
+        };
+
+        let (first, how) = config.allocate("svc", &settings, Protocol::Tcp).unwrap();
+        assert_eq!(how, Allocated::Assigned);
+        let (again, how) = config.allocate("svc", &settings, Protocol::Tcp).unwrap();
+        assert_eq!(how, Allocated::Existing(MappingSource::User));
+        assert_eq!(again.port, first.port);
+
+        let (block, _) = config
+            .allocate_block("brokers", 3, &settings, Protocol::Tcp)
+            .unwrap();
+        let (start, end) = block.port.bounds();
+        assert_eq!(end - start, 2);
+        assert!(matches!(
+            config.allocate_block("brokers", 2, &settings, Protocol::Tcp),
+            Err(Error::Validation(_))
+        ));
+
+        assert_eq!(config.release("svc").unwrap().port, first.port);
+        assert!(matches!(config.release("svc"), Err(Error::NotFound(_))));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn test_portspec_serialization_single() {
         let spec = PortSpec::Single(8080);
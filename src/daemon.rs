@@ -0,0 +1,888 @@
+use crate::config::{
+    Allocated, AllocationOverrides, Config, MappingSource, PortMapping, PortSpec, CONFIG_FILE,
+    DEFAULTS_FILE,
+};
+use crate::error::{self, Error, Result};
+use crate::expiry;
+use crate::lock::{self, ConfigLock};
+use crate::port_finder::Protocol;
+use crate::process::Owner;
+use crate::project::{Project, ProjectFile};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Socket the daemon listens on, in the config directory.
+pub const SOCKET_FILE: &str = "daemon.sock";
+
+/// How long the client waits for an answer before giving up on the daemon.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the daemon for the current config directory listens.
+pub fn socket_path() -> Result<PathBuf> {
+    Ok(Config::config_dir()?.join(SOCKET_FILE))
+}
+
+/// The namespace a request applies to, as the CLI works it out from
+/// `--project`, `--global` and the working directory.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Namespace {
+    /// Project namespace; `None` for global mappings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// Project root whose `.ports.toml` is consulted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_root: Option<PathBuf>,
+}
+
+/// A request, sent as one line of JSON such as `{"op":"resolve","name":"web"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// Resolve `name`, assigning a port (or a block of `block` ports) if needed
+    Allocate {
+        name: String,
+        #[serde(default)]
+        namespace: Namespace,
+        #[serde(default)]
+        overrides: AllocationOverrides,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        block: Option<u16>,
+        /// Lease duration in seconds for auto-assigned mappings
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+        /// Release the auto-assigned mapping when this process exits
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bind_pid: Option<u32>,
+    },
+    /// Remove `name`, returning a lease to its pool
+    Release {
+        name: String,
+        #[serde(default)]
+        namespace: Namespace,
+    },
+    /// Look `name` up without assigning anything
+    Resolve {
+        name: String,
+        #[serde(default)]
+        namespace: Namespace,
+    },
+    /// Project, user and system mappings visible from the namespace
+    List {
+        #[serde(default)]
+        namespace: Namespace,
+        /// Also list the defaults in effect
+        #[serde(default)]
+        all: bool,
+        /// List mappings of every project, not just the namespace's
+        #[serde(default)]
+        all_projects: bool,
+    },
+    /// Stop the daemon
+    Shutdown,
+}
+
+impl Request {
+    /// Whether the request may change the config, and so must hold the lock.
+    fn modifies_config(&self) -> bool {
+        matches!(self, Request::Allocate { .. } | Request::Release { .. })
+    }
+}
+
+/// A mapping in a response, with where it came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(flatten)]
+    pub mapping: PortMapping,
+    pub source: MappingSource,
+    /// Ports leased from a range mapping, in `list` responses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leases: Option<usize>,
+}
+
+/// The answer to a [`Request`], sent as one line of JSON.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    /// The allocated, released or resolved mapping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry: Option<Entry>,
+    /// How `allocate` came up with the mapping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allocated: Option<Allocated>,
+    /// Pool a released lease went back to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+    /// Result of `list`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<Entry>,
+    /// Set when the request failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Exit code for `error`, as from [`Error::exit_code`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<i32>,
+}
+
+impl Response {
+    fn failed(err: &Error) -> Self {
+        Response {
+            error: Some(err.to_string()),
+            code: Some(err.exit_code()),
+            ..Default::default()
+        }
+    }
+
+    /// Turns a failed response back into the [`Error`] it was made from.
+    fn into_result(self) -> Result<Self> {
+        let Some(message) = self.error.clone() else {
+            return Ok(self);
+        };
+        Err(match self.code.unwrap_or(error::EXIT_FAILURE) {
+            error::EXIT_INVALID => Error::Validation(message),
+            error::EXIT_NOT_FOUND => Error::NotFound(message),
+            error::EXIT_EXHAUSTED => Error::Exhausted(message),
+            code => Error::Daemon { message, code },
+        })
+    }
+
+    fn into_entry(self) -> Result<Entry> {
+        self.entry.ok_or_else(|| Error::Daemon {
+            message: "Daemon sent an empty response".to_string(),
+            code: error::EXIT_FAILURE,
+        })
+    }
+}
+
+/// Enough of a file's metadata to tell that it changed. Saves replace the
+/// file with a rename, so the inode catches two writes that land within the
+/// filesystem's timestamp granularity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fingerprint {
+    len: u64,
+    modified: Option<SystemTime>,
+    #[cfg(unix)]
+    inode: u64,
+}
+
+impl Fingerprint {
+    /// `None` if there is no file at `path`.
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Fingerprint {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(&metadata),
+        })
+    }
+}
+
+/// The daemon's copy of the config, defaults and system-wide mappings. It is
+/// kept in memory and only read again when one of the files changes on disk.
+struct State {
+    config: Config,
+    path: PathBuf,
+    lock_timeout: Duration,
+    /// What the config file looked like when last read or written
+    seen: Option<Fingerprint>,
+    /// The defaults and system-wide files, and what they looked like
+    others: Vec<(PathBuf, Option<Fingerprint>)>,
+    /// Parsed `.ports.toml` files by path
+    projects: HashMap<PathBuf, (Option<Fingerprint>, ProjectFile)>,
+}
+
+impl State {
+    fn load(path: &Path, lock_timeout: Duration) -> Result<Self> {
+        let mut others = vec![path.with_file_name(DEFAULTS_FILE)];
+        if let Some(dir) = Config::system_dir() {
+            others.push(dir.join(CONFIG_FILE));
+            others.push(dir.join(DEFAULTS_FILE));
+        }
+        let mut state = State {
+            config: Config::default(),
+            path: path.to_path_buf(),
+            lock_timeout,
+            seen: None,
+            others: others.into_iter().map(|path| (path, None)).collect(),
+            projects: HashMap::new(),
+        };
+        state.reload()?;
+        Ok(state)
+    }
+
+    /// Reads everything from disk. The files are looked at first, so one that
+    /// changes while being read is read again by the next [`State::refresh`].
+    fn reload(&mut self) -> Result<()> {
+        self.seen = Fingerprint::of(&self.path);
+        for (path, seen) in &mut self.others {
+            *seen = Fingerprint::of(path);
+        }
+        let mut config = Config::load_from(&self.path)?;
+        config.system = Config::load_system_ports()?;
+        config.defaults = Some(config.load_defaults()?);
+        self.config = config;
+        Ok(())
+    }
+
+    /// Picks up edits made without the daemon, e.g. by `set` or an editor,
+    /// at the cost of a few `stat` calls when nothing changed.
+    fn refresh(&mut self) -> Result<()> {
+        let changed = Fingerprint::of(&self.path) != self.seen
+            || self
+                .others
+                .iter()
+                .any(|(path, seen)| Fingerprint::of(path) != *seen);
+        if changed {
+            self.reload()?;
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) -> Result<()> {
+        self.config.save()?;
+        self.seen = Fingerprint::of(&self.path);
+        Ok(())
+    }
+
+    fn enter(&mut self, namespace: &Namespace) -> Result<()> {
+        self.config.scope = namespace.scope.clone();
+        self.config.project_file = match &namespace.project_root {
+            Some(root) => self.project_file(&Project {
+                name: namespace.scope.clone().unwrap_or_default(),
+                root: root.clone(),
+            })?,
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// The project's `.ports.toml`, parsed again only when it changed.
+    fn project_file(&mut self, project: &Project) -> Result<Option<ProjectFile>> {
+        let Some(path) = project.file_path() else {
+            return Ok(None);
+        };
+        let seen = Fingerprint::of(&path);
+        if let Some((cached, file)) = self.projects.get(&path) {
+            if *cached == seen {
+                return Ok(Some(file.clone()));
+            }
+        }
+        let file = ProjectFile::read(&path)?;
+        self.projects.insert(path, (seen, file.clone()));
+        Ok(Some(file))
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Response> {
+        // Lookups are answered from memory; changes take the same lock as
+        // the CLI, which still writes the files directly for other commands
+        let lock = if request.modifies_config() {
+            let lock_path = self.path.with_file_name(lock::LOCK_FILE);
+            Some(ConfigLock::acquire_at(&lock_path, self.lock_timeout)?)
+        } else {
+            None
+        };
+        self.refresh()?;
+        if lock.is_some() && !self.config.release_orphans().is_empty() {
+            self.save()?;
+        }
+        let response = self.apply(request);
+        self.config.scope = None;
+        self.config.project_file = None;
+        if response.is_err() && lock.is_some() {
+            // Drop whatever the failed request changed before it failed
+            self.reload()?;
+        }
+        response
+    }
+
+    fn apply(&mut self, request: Request) -> Result<Response> {
+        match request {
+            Request::Allocate {
+                name,
+                namespace,
+                overrides,
+                protocol,
+                block,
+                ttl,
+                bind_pid,
+            } => {
+                self.enter(&namespace)?;
+                let owner = bind_pid.map(Owner::running).transpose()?;
+                let settings = overrides.apply(&self.config.allocation);
+                let (mapping, allocated) = match block {
+                    Some(0) => {
+                        return Err(Error::Validation(
+                            "Block size must be at least 1".to_string(),
+                        ))
+                    }
+                    Some(count) => self
+                        .config
+                        .allocate_block(&name, count, &settings, protocol)?,
+                    None => self.config.allocate(&name, &settings, protocol)?,
+                };
+                let touched = self.config.touch(&name, ttl, expiry::now());
+                let bound = match owner {
+                    Some(owner) => self.config.bind(&name, owner)?,
+                    None => false,
+                };
+                if touched || bound || !matches!(allocated, Allocated::Existing(_)) {
+                    self.save()?;
+                }
+                let source = match allocated {
+                    Allocated::Existing(source) => source,
+                    Allocated::Leased | Allocated::Assigned => MappingSource::User,
+                };
+                Ok(Response {
+                    entry: Some(Entry {
+                        mapping,
+                        source,
+                        leases: None,
+                    }),
+                    allocated: Some(allocated),
+                    ..Default::default()
+                })
+            }
+            Request::Release { name, namespace } => {
+                self.enter(&namespace)?;
+                let pool = self.config.pool_of(&name).map(|pool| pool.name);
+                let mapping = self.config.release(&name)?;
+                self.save()?;
+                Ok(Response {
+                    entry: Some(Entry {
+                        mapping,
+                        source: MappingSource::User,
+                        leases: None,
+                    }),
+                    pool,
+                    ..Default::default()
+                })
+            }
+            Request::Resolve { name, namespace } => {
+                self.enter(&namespace)?;
+                let (mapping, source) = self.config.resolve(&name).ok_or_else(|| {
+                    Error::NotFound(format!(
+                        "Port mapping not found: {}",
+                        self.config.qualify(&name)
+                    ))
+                })?;
+                Ok(Response {
+                    entry: Some(Entry {
+                        mapping,
+                        source,
+                        leases: None,
+                    }),
+                    ..Default::default()
+                })
+            }
+            Request::List {
+                namespace,
+                all,
+                all_projects,
+            } => {
+                self.enter(&namespace)?;
+                let ports = if all_projects {
+                    self.config.list_ports().to_vec()
+                } else {
+                    self.config.visible_ports()
+                };
+                let mut listed = vec![
+                    (self.config.project_file_ports(), MappingSource::Project),
+                    (ports, MappingSource::User),
+                    (self.config.system_ports(), MappingSource::System),
+                ];
+                if all {
+                    let defaults = self.config.load_defaults()?;
+                    listed.push((
+                        self.config.effective_defaults(&defaults),
+                        MappingSource::Default,
+                    ));
+                }
+                let config = &self.config;
+                let entries = listed
+                    .into_iter()
+                    .flat_map(|(ports, source)| {
+                        ports.into_iter().map(move |mapping| Entry {
+                            leases: matches!(mapping.port, PortSpec::Range { .. })
+                                .then(|| config.pool_leases(&mapping).len()),
+                            mapping,
+                            source,
+                        })
+                    })
+                    .collect();
+                Ok(Response {
+                    entries,
+                    ..Default::default()
+                })
+            }
+            Request::Shutdown => Ok(Response::default()),
+        }
+    }
+}
+
+/// Serves requests on the socket at [`socket_path`] for the default config
+/// file until a `shutdown` request arrives. Changes wait up to `lock_timeout`
+/// for the config lock.
+pub fn serve(socket: &Path, lock_timeout: Duration) -> Result<()> {
+    serve_with(socket, &Config::config_path()?, lock_timeout)
+}
+
+/// Like [`serve`], for the config file at `config_path`.
+#[cfg(unix)]
+pub fn serve_with(socket: &Path, config_path: &Path, lock_timeout: Duration) -> Result<()> {
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, PoisonError};
+    use std::thread;
+
+    if socket.exists() {
+        match UnixStream::connect(socket) {
+            Ok(_) => {
+                return Err(Error::Validation(format!(
+                    "A daemon is already listening on {}",
+                    socket.display()
+                )))
+            }
+            // Left behind by a daemon that was killed
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                fs::remove_file(socket).map_err(|e| Error::io(socket, e))?;
+            }
+            Err(e) => return Err(Error::io(socket, e)),
+        }
+    }
+    if let Some(dir) = socket.parent() {
+        fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))?;
+    }
+
+    let state = Arc::new(Mutex::new(State::load(config_path, lock_timeout)?));
+    let listener = bind_private(socket)?;
+    let stopping = Arc::new(AtomicBool::new(false));
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(stream) = stream else { continue };
+        let state = Arc::clone(&state);
+        let stopping = Arc::clone(&stopping);
+        let socket = socket.to_path_buf();
+        thread::spawn(move || {
+            let handle = |request| {
+                let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+                state.handle(request)
+            };
+            if connection(stream, handle) {
+                stopping.store(true, Ordering::SeqCst);
+                // Wake up the accept loop so it sees the flag
+                let _ = UnixStream::connect(&socket);
+            }
+        });
+    }
+
+    fs::remove_file(socket).map_err(|e| Error::io(socket, e))
+}
+
+/// Binds `socket` so that only the current user can ever connect: the socket
+/// is created in a private directory, restricted, and then linked into place.
+#[cfg(unix)]
+fn bind_private(socket: &Path) -> Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+
+    let staging = socket.with_file_name(format!(".{}.{}", SOCKET_FILE, std::process::id()));
+    let _ = fs::remove_dir_all(&staging);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .map_err(|e| Error::io(&staging, e))?;
+    let staged = staging.join(SOCKET_FILE);
+    let bound = UnixListener::bind(&staged)
+        .and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+            // Unlike a rename, this fails if another daemon got there first
+            fs::hard_link(&staged, socket)?;
+            Ok(listener)
+        })
+        .map_err(|e| Error::io(socket, e));
+    let _ = fs::remove_dir_all(&staging);
+    bound
+}
+
+#[cfg(not(unix))]
+pub fn serve_with(socket: &Path, _config_path: &Path, _lock_timeout: Duration) -> Result<()> {
+    Err(Error::io(socket, unsupported()))
+}
+
+/// Answers requests from one client, one line at a time. Returns whether the
+/// client asked the daemon to shut down.
+#[cfg(unix)]
+fn connection(
+    stream: std::os::unix::net::UnixStream,
+    mut handle: impl FnMut(Request) -> Result<Response>,
+) -> bool {
+    use std::io::{BufRead, BufReader, Write};
+
+    let Ok(reader) = stream.try_clone() else {
+        return false;
+    };
+    let mut writer = stream;
+    for line in BufReader::new(reader).lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (response, shutdown) = match serde_json::from_str::<Request>(&line) {
+            Ok(Request::Shutdown) => (Response::default(), true),
+            Ok(request) => (
+                handle(request).unwrap_or_else(|e| Response::failed(&e)),
+                false,
+            ),
+            Err(e) => (
+                Response::failed(&Error::Validation(format!("Invalid request: {}", e))),
+                false,
+            ),
+        };
+        let Ok(json) = serde_json::to_string(&response) else {
+            break;
+        };
+        if writeln!(writer, "{}", json).is_err() || shutdown {
+            return shutdown;
+        }
+    }
+    false
+}
+
+/// Connection to a running daemon.
+pub struct Client {
+    #[cfg(unix)]
+    reader: io::BufReader<std::os::unix::net::UnixStream>,
+    #[cfg(unix)]
+    writer: std::os::unix::net::UnixStream,
+    path: PathBuf,
+}
+
+impl Client {
+    /// Connects to the daemon listening on `path`, or returns `None` if no
+    /// daemon is running there.
+    #[cfg(unix)]
+    pub fn connect(path: &Path) -> Result<Option<Self>> {
+        use std::os::unix::net::UnixStream;
+
+        let writer = match UnixStream::connect(path) {
+            Ok(stream) => stream,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(Error::io(path, e)),
+        };
+        writer
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .map_err(|e| Error::io(path, e))?;
+        let reader = writer.try_clone().map_err(|e| Error::io(path, e))?;
+        Ok(Some(Client {
+            reader: io::BufReader::new(reader),
+            writer,
+            path: path.to_path_buf(),
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect(_path: &Path) -> Result<Option<Self>> {
+        Ok(None)
+    }
+
+    /// Sends `request` and waits for the answer. Failures reported by the
+    /// daemon come back as the matching [`Error`].
+    #[cfg(unix)]
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        use std::io::{BufRead, Write};
+
+        let json = serde_json::to_string(request)?;
+        writeln!(self.writer, "{}", json).map_err(|e| Error::io(&self.path, e))?;
+        let mut line = String::new();
+        let read = self
+            .reader
+            .read_line(&mut line)
+            .map_err(|e| Error::io(&self.path, e))?;
+        if read == 0 {
+            let closed =
+                io::Error::new(io::ErrorKind::UnexpectedEof, "daemon closed the connection");
+            return Err(Error::io(&self.path, closed));
+        }
+        let response: Response = serde_json::from_str(&line).map_err(|e| Error::Daemon {
+            message: format!("Invalid response from daemon: {}", e),
+            code: error::EXIT_FAILURE,
+        })?;
+        response.into_result()
+    }
+
+    #[cfg(not(unix))]
+    pub fn request(&mut self, _request: &Request) -> Result<Response> {
+        Err(Error::io(&self.path, unsupported()))
+    }
+
+    /// Looks `name` up without assigning anything.
+    pub fn resolve(&mut self, name: &str, namespace: &Namespace) -> Result<Entry> {
+        self.request(&Request::Resolve {
+            name: name.to_string(),
+            namespace: namespace.clone(),
+        })?
+        .into_entry()
+    }
+
+    /// Removes `name`, returning the mapping and the pool it was leased from.
+    pub fn release(
+        &mut self,
+        name: &str,
+        namespace: &Namespace,
+    ) -> Result<(PortMapping, Option<String>)> {
+        let response = self.request(&Request::Release {
+            name: name.to_string(),
+            namespace: namespace.clone(),
+        })?;
+        let pool = response.pool.clone();
+        Ok((response.into_entry()?.mapping, pool))
+    }
+
+    /// Mappings visible from `namespace`, with the defaults in effect if
+    /// `all` is set and the mappings of every project if `all_projects` is.
+    pub fn list(
+        &mut self,
+        namespace: &Namespace,
+        all: bool,
+        all_projects: bool,
+    ) -> Result<Vec<Entry>> {
+        let response = self.request(&Request::List {
+            namespace: namespace.clone(),
+            all,
+            all_projects,
+        })?;
+        Ok(response.entries)
+    }
+
+    /// Asks the daemon to stop.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.request(&Request::Shutdown).map(|_| ())
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the daemon needs Unix domain sockets",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortSpec;
+
+    #[test]
+    fn test_request_wire_format() {
+        let request: Request =
+            serde_json::from_str(r#"{"op":"allocate","name":"web","block":3}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::Allocate {
+                name: "web".to_string(),
+                namespace: Namespace::default(),
+                overrides: AllocationOverrides::default(),
+                protocol: Protocol::Tcp,
+                block: Some(3),
+                ttl: None,
+                bind_pid: None,
+            }
+        );
+        let json = serde_json::to_string(&Request::Release {
+            name: "db".to_string(),
+            namespace: Namespace {
+                scope: Some("shop".to_string()),
+                project_root: None,
+            },
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            r#"{"op":"release","name":"db","namespace":{"scope":"shop"}}"#
+        );
+    }
+
+    #[test]
+    fn test_response_errors_map_back() {
+        let err = Response::failed(&Error::NotFound("Port mapping not found: x".to_string()))
+            .into_result()
+            .unwrap_err();
+        assert!(matches!(err, Error::NotFound(_)));
+
+        let err = Response::failed(&Error::LockTimeout {
+            path: PathBuf::from("/tmp/.lock"),
+            timeout: Duration::from_secs(1),
+        })
+        .into_result()
+        .unwrap_err();
+        assert_eq!(err.exit_code(), error::EXIT_LOCKED);
+    }
+
+    #[test]
+    fn test_state_rereads_only_changed_files() {
+        let dir = std::env::temp_dir().join(format!("ports-manager-state-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let mut state = State::load(&path, lock::DEFAULT_LOCK_TIMEOUT).unwrap();
+
+        // Unchanged files aren't read again, so in-memory state survives
+        state
+            .config
+            .add_port("web".to_string(), PortSpec::Single(43300), None);
+        state.refresh().unwrap();
+        assert!(state.config.find_port("web").is_some());
+        state.save().unwrap();
+        state.refresh().unwrap();
+        assert!(state.config.find_port("web").is_some());
+
+        // Two saves in quick succession are both noticed
+        for port in [43301, 43302] {
+            let mut edited = Config::load_from(&path).unwrap();
+            edited.add_port(format!("api-{}", port), PortSpec::Single(port), None);
+            edited.save().unwrap();
+            state.refresh().unwrap();
+            assert!(state.config.find_port(&format!("api-{}", port)).is_some());
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_socket_is_replaced() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = std::env::temp_dir().join(format!("ports-manager-stale-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join(SOCKET_FILE);
+        // Nobody listens on a socket whose listener is gone
+        drop(UnixListener::bind(&socket).unwrap());
+
+        // Binding never replaces a file by itself; serving removes the stale one first
+        let listener = bind_private(&socket);
+        assert!(matches!(listener, Err(Error::Io { .. })));
+        let (socket, config_path) = (socket.clone(), dir.join("config.toml"));
+        let server = std::thread::spawn({
+            let socket = socket.clone();
+            move || serve_with(&socket, &config_path, lock::DEFAULT_LOCK_TIMEOUT)
+        });
+        let mut client = None;
+        for _ in 0..100 {
+            client = Client::connect(&socket).unwrap();
+            if client.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        client.expect("daemon did not start").shutdown().unwrap();
+        server.join().unwrap().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_daemon_round_trip() {
+        use std::thread;
+
+        let dir = std::env::temp_dir().join(format!("ports-manager-daemon-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join(SOCKET_FILE);
+        let config_path = dir.join("config.toml");
+
+        let server = {
+            let (socket, config_path) = (socket.clone(), config_path.clone());
+            thread::spawn(move || serve_with(&socket, &config_path, lock::DEFAULT_LOCK_TIMEOUT))
+        };
+        let mut client = None;
+        for _ in 0..100 {
+            client = Client::connect(&socket).unwrap();
+            if client.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut client = client.expect("daemon did not start");
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&socket).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let err = serve_with(&socket, &config_path, lock::DEFAULT_LOCK_TIMEOUT).unwrap_err();
+        assert!(err.to_string().contains("already listening"));
+
+        let allocate = Request::Allocate {
+            name: "web".to_string(),
+            namespace: Namespace::default(),
+            overrides: AllocationOverrides {
+                range: Some(PortSpec::Range {
+                    start: 43100,
+                    end: 43199,
+                }),
+                ..Default::default()
+            },
+            protocol: Protocol::Tcp,
+            block: None,
+            ttl: None,
+            bind_pid: None,
+        };
+        let first = client.request(&allocate).unwrap();
+        assert_eq!(first.allocated, Some(Allocated::Assigned));
+        let second = client.request(&allocate).unwrap();
+        assert_eq!(
+            second.allocated,
+            Some(Allocated::Existing(MappingSource::User))
+        );
+        let port = first.entry.unwrap().mapping.port;
+        assert_eq!(second.entry.unwrap().mapping.port, port);
+
+        // Persisted, so the CLI sees it without the daemon
+        let on_disk = Config::load_from(&config_path).unwrap();
+        assert_eq!(on_disk.find_port("web").unwrap().port, port);
+
+        let namespace = Namespace::default();
+        assert_eq!(
+            client.resolve("web", &namespace).unwrap().mapping.port,
+            port
+        );
+        assert_eq!(client.list(&namespace, false, false).unwrap().len(), 1);
+        let with_defaults = client.list(&namespace, true, false).unwrap();
+        assert!(with_defaults.len() > 1);
+        assert!(with_defaults
+            .iter()
+            .skip(1)
+            .all(|entry| entry.source == MappingSource::Default));
+
+        // Edits made without the daemon are seen by the next request, and
+        // aren't overwritten by the daemon's own saves
+        let mut edited = Config::load_from(&config_path).unwrap();
+        edited.add_port("api".to_string(), PortSpec::Single(43200), None);
+        edited.save().unwrap();
+        assert_eq!(client.list(&namespace, false, false).unwrap().len(), 2);
+        client.release("api", &namespace).unwrap();
+        client.release("web", &namespace).unwrap();
+        let err = client.resolve("web", &namespace).unwrap_err();
+        assert_eq!(err.exit_code(), error::EXIT_NOT_FOUND);
+
+        client.shutdown().unwrap();
+        server.join().unwrap().unwrap();
+        assert!(!socket.exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::config::{
+    self, Config, DefaultsConfig, Migration, PortMapping, CONFIG_MIGRATIONS, CONFIG_SCHEMA,
+    DEFAULTS_MIGRATIONS, DEFAULTS_SCHEMA,
+};
+use crate::error::Result;
+use crate::project::{Project, PROJECT_FILE};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Ports below this need root (or CAP_NET_BIND_SERVICE) to bind.
+const PRIVILEGED_BELOW: u16 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A single problem reported by `doctor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, message: String) -> Self {
+        Finding { severity, message }
+    }
+}
+
+/// Reads every configuration file without creating or repairing anything and
+/// checks the result from the `scope` namespace, with `project`'s
+/// `.ports.toml` if given. Unreadable files are reported instead of aborting.
+pub fn run(
+    scope: Option<&str>,
+    project: Option<&Project>,
+    ephemeral: Option<(u16, u16)>,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    let config_schema = (CONFIG_SCHEMA, CONFIG_MIGRATIONS);
+    let defaults_schema = (DEFAULTS_SCHEMA, DEFAULTS_MIGRATIONS);
+    let mut config: Config = read_file(
+        Config::config_path(),
+        "config.toml",
+        config_schema,
+        &mut findings,
+    )
+    .unwrap_or_default();
+    let mut defaults: Option<DefaultsConfig> = read_file(
+        DefaultsConfig::defaults_path(),
+        "defaults.toml",
+        defaults_schema,
+        &mut findings,
+    );
+    if defaults.is_none() {
+        if let Some(path) = DefaultsConfig::system_path() {
+            defaults = parse_file(&path, defaults_schema, &mut findings);
+        }
+    }
+    let defaults = defaults.unwrap_or_default();
+
+    match Config::load_system_ports() {
+        Ok(ports) => config.system = ports,
+        Err(e) => findings.push(Finding::new(
+            Severity::Error,
+            format!("Cannot read system config: {}", e),
+        )),
+    }
+
+    config.scope = scope.map(str::to_string);
+    if let Some(project) = project {
+        match project.load_file() {
+            Ok(file) => config.project_file = file,
+            Err(e) => findings.push(Finding::new(
+                Severity::Error,
+                format!("Cannot read {}: {}", PROJECT_FILE, e),
+            )),
+        }
+    }
+
+    findings.extend(check(&config, &defaults, ephemeral));
+    findings
+}
+
+/// Current schema version of a file and the migrations leading up to it.
+type Schema = (u32, &'static [Migration]);
+
+fn read_file<T: DeserializeOwned>(
+    path: Result<std::path::PathBuf>,
+    label: &str,
+    schema: Schema,
+    findings: &mut Vec<Finding>,
+) -> Option<T> {
+    let path = match path {
+        Ok(path) => path,
+        Err(e) => {
+            findings.push(Finding::new(
+                Severity::Error,
+                format!("Cannot locate {}: {}", label, e),
+            ));
+            return None;
+        }
+    };
+    parse_file(&path, schema, findings)
+}
+
+fn parse_file<T: DeserializeOwned>(
+    path: &Path,
+    (current, migrations): Schema,
+    findings: &mut Vec<Finding>,
+) -> Option<T> {
+    if !path.exists() {
+        return None;
+    }
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            findings.push(Finding::new(
+                Severity::Error,
+                format!("Cannot read {}: {}", path.display(), e),
+            ));
+            return None;
+        }
+    };
+    match config::parse_versioned(path, &content, current, migrations) {
+        Ok((value, found)) => {
+            if found < current {
+                findings.push(Finding::new(
+                    Severity::Note,
+                    format!(
+                        "{} uses schema version {} and will be upgraded to {} on next use",
+                        path.display(),
+                        found,
+                        current
+                    ),
+                ));
+            }
+            Some(value)
+        }
+        Err(e) => {
+            findings.push(Finding::new(
+                Severity::Error,
+                format!("{} is not valid: {}", path.display(), e),
+            ));
+            None
+        }
+    }
+}
+
+/// Checks loaded configuration for duplicates, overlapping ports, clashes
+/// with defaults, and ports that are awkward to bind.
+pub fn check(
+    config: &Config,
+    defaults: &DefaultsConfig,
+    ephemeral: Option<(u16, u16)>,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    // Duplicate names within one namespace
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for mapping in config.list_ports() {
+        *counts.entry(mapping.qualified_name()).or_default() += 1;
+    }
+    for (name, count) in counts.into_iter().filter(|(_, count)| *count > 1) {
+        findings.push(Finding::new(
+            Severity::Error,
+            format!(
+                "'{}' is defined {} times; only the first is used",
+                name, count
+            ),
+        ));
+    }
+
+    // Overlaps between any two user, project or system ports
+    let mut mappings = config.project_file_ports();
+    mappings.extend(config.list_ports().iter().cloned());
+    mappings.extend(config.system_ports());
+    for (i, a) in mappings.iter().enumerate() {
+        for b in &mappings[i + 1..] {
+            let shared = a.shared || b.shared;
+            if !shared && a.port.overlaps(&b.port) && !a.is_lease_of(b) && !b.is_lease_of(a) {
+                findings.push(Finding::new(
+                    Severity::Error,
+                    format!("{} overlaps {}", describe(a), describe(b)),
+                ));
+            }
+        }
+    }
+
+    // Collisions with defaults that are still in effect
+    for default in defaults
+        .ports
+        .iter()
+        .filter(|d| !config.ignored_defaults.contains(&d.name))
+    {
+        for mapping in &mappings {
+            if mapping.name == default.name {
+                if mapping.project.is_none() && mapping.port != default.port {
+                    findings.push(Finding::new(
+                        Severity::Note,
+                        format!(
+                            "{} overrides the default port {}",
+                            describe(mapping),
+                            default.port.display()
+                        ),
+                    ));
+                }
+            } else if !mapping.shared && mapping.port.overlaps(&default.port) {
+                findings.push(Finding::new(
+                    Severity::Warning,
+                    format!(
+                        "{} collides with default {}; add \"{}\" to ignored_defaults if you don't use it",
+                        describe(mapping),
+                        describe(default),
+                        default.name
+                    ),
+                ));
+            }
+        }
+    }
+
+    // Ports that are awkward to bind
+    for mapping in &mappings {
+        let (start, end) = mapping.port.bounds();
+        if start < PRIVILEGED_BELOW {
+            findings.push(Finding::new(
+                Severity::Warning,
+                format!(
+                    "{} uses privileged ports (below {}), which need root to bind",
+                    describe(mapping),
+                    PRIVILEGED_BELOW
+                ),
+            ));
+        }
+        if let Some((eph_start, eph_end)) = ephemeral {
+            if start <= eph_end && eph_start <= end {
+                findings.push(Finding::new(
+                    Severity::Warning,
+                    format!(
+                        "{} is inside the OS ephemeral range {}-{} and may be taken by outgoing connections",
+                        describe(mapping),
+                        eph_start,
+                        eph_end
+                    ),
+                ));
+            }
+        }
+    }
+
+    findings
+}
+
+fn describe(mapping: &PortMapping) -> String {
+    format!(
+        "'{}' ({})",
+        mapping.qualified_name(),
+        mapping.port.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortSpec;
+
+    fn messages(findings: &[Finding], severity: Severity) -> Vec<String> {
+        findings
+            .iter()
+            .filter(|f| f.severity == severity)
+            .map(|f| f.message.clone())
+            .collect()
+    }
+
+    fn defaults() -> DefaultsConfig {
+        let mut defaults = DefaultsConfig::default();
+        defaults.ports = vec![
+            PortMapping::new("postgres", PortSpec::Single(5432), None),
+            PortMapping::new("redis", PortSpec::Single(6379), None),
+        ];
+        defaults
+    }
+
+    #[test]
+    fn test_check_clean_config() {
+        let mut config = Config::default();
+        config.add_port("web".to_string(), PortSpec::Single(8080), None);
+        assert!(check(&config, &defaults(), None).is_empty());
+    }
+
+    #[test]
+    fn test_check_duplicates_and_overlaps() {
+        let mut config = Config::default();
+        config.add_port("web".to_string(), PortSpec::Single(8080), None);
+        config.add_port("web".to_string(), PortSpec::Single(8081), None);
+        config.add_port(
+            "pool".to_string(),
+            PortSpec::Range {
+                start: 8075,
+                end: 8085,
+            },
+            None,
+        );
+
+        let errors = messages(&check(&config, &defaults(), None), Severity::Error);
+        assert!(errors.contains(&"'web' is defined 2 times; only the first is used".to_string()));
+        assert!(errors.contains(&"'web' (8080) overlaps 'pool' (8075-8085)".to_string()));
+        assert!(errors.contains(&"'web' (8081) overlaps 'pool' (8075-8085)".to_string()));
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn test_check_ignores_pool_leases() {
+        let mut config = Config::default();
+        config.add_port(
+            "pool".to_string(),
+            PortSpec::Range {
+                start: 9000,
+                end: 9010,
+            },
+            None,
+        );
+        config.add_port("pool/worker".to_string(), PortSpec::Single(9003), None);
+        assert!(check(&config, &defaults(), None).is_empty());
+    }
+
+    #[test]
+    fn test_check_default_collisions() {
+        let mut config = Config::default();
+        config.add_port("cache".to_string(), PortSpec::Single(6379), None);
+        config.add_port("postgres".to_string(), PortSpec::Single(5433), None);
+
+        let findings = check(&config, &defaults(), None);
+        let warnings = messages(&findings, Severity::Warning);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'cache' (6379) collides with default 'redis' (6379)"));
+        assert_eq!(
+            messages(&findings, Severity::Note),
+            vec!["'postgres' (5433) overrides the default port 5432".to_string()]
+        );
+
+        config.ignored_defaults.push("redis".to_string());
+        assert!(messages(&check(&config, &defaults(), None), Severity::Warning).is_empty());
+    }
+
+    #[test]
+    fn test_check_privileged_and_ephemeral() {
+        let mut config = Config::default();
+        config.add_port("http".to_string(), PortSpec::Single(80), None);
+        config.add_port("worker".to_string(), PortSpec::Single(40000), None);
+
+        let warnings = messages(
+            &check(&config, &defaults(), Some((32768, 60999))),
+            Severity::Warning,
+        );
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("'http' (80) uses privileged ports"));
+        assert!(warnings[1].contains("'worker' (40000) is inside the OS ephemeral range"));
+    }
+
+    #[test]
+    fn test_parse_file_reports_invalid_toml() {
+        let dir = std::env::temp_dir().join(format!("ports-manager-doctor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "[[ports]\nname = ").unwrap();
+
+        let schema = (CONFIG_SCHEMA, CONFIG_MIGRATIONS);
+        let mut findings = Vec::new();
+        assert!(parse_file::<Config>(&path, schema, &mut findings).is_none());
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert!(findings[0].message.contains("is not valid"));
+
+        // Missing files are fine: they are created on first use
+        findings.clear();
+        assert!(parse_file::<Config>(&dir.join("missing.toml"), schema, &mut findings).is_none());
+        assert!(findings.is_empty());
+
+        // Old schemas are upgraded later, newer ones can't be read
+        fs::write(&path, "ports = []\n").unwrap();
+        assert!(parse_file::<Config>(&path, schema, &mut findings).is_some());
+        assert_eq!(findings[0].severity, Severity::Note);
+        findings.clear();
+        fs::write(&path, "schema_version = 99\n").unwrap();
+        assert!(parse_file::<Config>(&path, schema, &mut findings).is_none());
+        assert!(findings[0].message.contains("schema version 99"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
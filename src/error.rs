@@ -0,0 +1,257 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Errors returned by the library.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed
+    Io { path: PathBuf, source: io::Error },
+    /// The home directory, and so the config location, couldn't be determined
+    NoHomeDir,
+    /// A config file isn't valid TOML or doesn't match the expected layout
+    Parse {
+        path: PathBuf,
+        /// 1-based line and column of the problem, when known
+        position: Option<(usize, usize)>,
+        source: Box<toml::de::Error>,
+        /// A `.bak` copy that `restore-backup` could bring back
+        backup: Option<PathBuf>,
+    },
+    /// Rendering output failed
+    Serialize(String),
+    /// Input that doesn't make sense, e.g. a malformed port
+    Validation(String),
+    /// No mapping with this name
+    NotFound(String),
+    /// No free port left to hand out
+    Exhausted(String),
+    /// Another process held the config lock for longer than the timeout
+    LockTimeout { path: PathBuf, timeout: Duration },
+    /// The daemon failed a request; `code` is the exit code it reported
+    Daemon { message: String, code: i32 },
+    /// A config file was written by a newer version with a schema we don't know
+    UnsupportedSchema {
+        path: PathBuf,
+        found: u32,
+        supported: u32,
+    },
+}
+
+/// Shorthand for results with the library's [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Exit code for failures without a more specific code, e.g. `doctor` finding problems.
+pub const EXIT_FAILURE: i32 = 1;
+/// Invalid input: malformed ports or options, or a port that conflicts with others.
+pub const EXIT_INVALID: i32 = 2;
+/// The named mapping (or backup) doesn't exist.
+pub const EXIT_NOT_FOUND: i32 = 3;
+/// No free port or block left in the range or pool.
+pub const EXIT_EXHAUSTED: i32 = 4;
+/// A config file is corrupt.
+pub const EXIT_CORRUPT: i32 = 5;
+/// Timed out waiting for the config lock.
+pub const EXIT_LOCKED: i32 = 6;
+/// Reading or writing a file failed.
+pub const EXIT_IO: i32 = 7;
+/// A config file needs a newer ports-manager.
+pub const EXIT_UNSUPPORTED: i32 = 8;
+
+impl Error {
+    pub(crate) fn io(path: &Path, source: io::Error) -> Self {
+        Error::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// A parse error for `path`, locating the problem within `content`.
+    pub(crate) fn parse(
+        path: &Path,
+        content: &str,
+        source: toml::de::Error,
+        backup: Option<PathBuf>,
+    ) -> Self {
+        let position = source.span().map(|span| line_column(content, span.start));
+        Error::Parse {
+            path: path.to_path_buf(),
+            position,
+            source: Box::new(source),
+            backup,
+        }
+    }
+
+    /// The process exit code the CLI uses for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Validation(_) => EXIT_INVALID,
+            Error::NotFound(_) => EXIT_NOT_FOUND,
+            Error::Exhausted(_) => EXIT_EXHAUSTED,
+            Error::Parse { .. } => EXIT_CORRUPT,
+            Error::LockTimeout { .. } => EXIT_LOCKED,
+            Error::Io { .. } | Error::NoHomeDir => EXIT_IO,
+            Error::UnsupportedSchema { .. } => EXIT_UNSUPPORTED,
+            Error::Daemon { code, .. } => *code,
+            Error::Serialize(_) => EXIT_FAILURE,
+        }
+    }
+}
+
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    (line, column)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::NoHomeDir => f.write_str("Could not find home directory"),
+            Error::Parse {
+                path,
+                position,
+                source,
+                backup,
+            } => {
+                match position {
+                    Some((line, column)) => write!(
+                        f,
+                        "{} is corrupt at line {}, column {}: {}",
+                        path.display(),
+                        line,
+                        column,
+                        source.message()
+                    )?,
+                    None => write!(f, "{} is corrupt: {}", path.display(), source.message())?,
+                }
+                if let Some(backup) = backup {
+                    write!(
+                        f,
+                        "\nA backup exists at {}; run `ports-manager restore-backup` to restore it",
+                        backup.display()
+                    )?;
+                }
+                Ok(())
+            }
+            Error::Serialize(message)
+            | Error::Validation(message)
+            | Error::NotFound(message)
+            | Error::Exhausted(message)
+            | Error::Daemon { message, .. } => f.write_str(message),
+            Error::LockTimeout { path, timeout } => write!(
+                f,
+                "Timed out after {:.1}s waiting for lock on {} \
+                 (another ports-manager process is holding it)",
+                timeout.as_secs_f64(),
+                path.display()
+            ),
+            Error::UnsupportedSchema {
+                path,
+                found,
+                supported,
+            } => write!(
+                f,
+                "{} has schema version {}, but this ports-manager only supports up to {}; \
+                 upgrade ports-manager to use it",
+                path.display(),
+                found,
+                supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Parse { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(err: toml::ser::Error) -> Self {
+        Error::Serialize(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serialize(err.to_string())
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(err: serde_yaml::Error) -> Self {
+        Error::Serialize(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_error_reports_position() {
+        let content = "[[ports]]\nname = \"web\"\nport = \"soon\"\n";
+        let source = toml::from_str::<toml::Table>("x = [").unwrap_err();
+        let err = Error::parse(Path::new("config.toml"), "x = [", source, None);
+        assert!(matches!(
+            err,
+            Error::Parse {
+                position: Some((1, _)),
+                ..
+            }
+        ));
+
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Port {
+            port: u16,
+        }
+        #[derive(Debug, serde::Deserialize)]
+        #[allow(dead_code)]
+        struct Ports {
+            ports: Vec<Port>,
+        }
+        let source = toml::from_str::<Ports>(content).unwrap_err();
+        let message = Error::parse(Path::new("config.toml"), content, source, None).to_string();
+        assert!(message.starts_with("config.toml is corrupt at line 3, column 8:"));
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let errors = [
+            Error::Validation(String::new()),
+            Error::NotFound(String::new()),
+            Error::Exhausted(String::new()),
+            Error::Parse {
+                path: PathBuf::new(),
+                position: None,
+                source: Box::new(toml::from_str::<toml::Table>("=").unwrap_err()),
+                backup: None,
+            },
+            Error::LockTimeout {
+                path: PathBuf::new(),
+                timeout: Duration::ZERO,
+            },
+            Error::NoHomeDir,
+            Error::UnsupportedSchema {
+                path: PathBuf::new(),
+                found: 2,
+                supported: 1,
+            },
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(Error::exit_code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&EXIT_FAILURE));
+    }
+}
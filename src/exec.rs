@@ -0,0 +1,183 @@
+use ports_manager::error;
+use ports_manager::port_finder::HeldSocket;
+use std::ffi::OsString;
+use std::process::Command;
+
+/// Runs `program` with the given extra environment variables and never returns.
+///
+/// On Unix the current process is replaced with `execvp`, so signals from the
+/// terminal or a supervisor reach the command directly and its exit status is
+/// the exit status of `ports-manager exec`. Elsewhere the command is spawned,
+/// waited for, and its exit code propagated.
+///
+/// `sockets` are handed to the command as file descriptors 3, 4, ... following
+/// the systemd socket activation convention (Unix only).
+pub fn run(
+    program: &OsString,
+    args: &[OsString],
+    env: &[(String, String)],
+    sockets: &[(String, HeldSocket)],
+) -> ! {
+    let mut command = Command::new(program);
+    command.args(args);
+    for (key, value) in env {
+        command.env(key, value);
+    }
+    // Kept open until the command is started
+    #[cfg(unix)]
+    let _staged = match pass_sockets(&mut command, sockets) {
+        Ok(staged) => staged,
+        Err(e) => {
+            eprintln!("Error passing sockets: {}", e);
+            std::process::exit(error::EXIT_FAILURE);
+        }
+    };
+    #[cfg(not(unix))]
+    let _ = sockets;
+    run_command(command, program)
+}
+
+/// Arranges for `sockets` to be open as fds 3, 4, ... in the command, with
+/// `LISTEN_FDS` giving their count, `LISTEN_PID` the command's PID (ours, as
+/// `run` execs) and `LISTEN_FDNAMES` their names separated by colons.
+///
+/// Returns copies of the sockets that must stay open until the command has
+/// been spawned or exec'd. Does nothing if `sockets` is empty.
+#[cfg(unix)]
+fn pass_sockets(
+    command: &mut Command,
+    sockets: &[(String, HeldSocket)],
+) -> std::io::Result<Vec<std::os::unix::io::OwnedFd>> {
+    use std::io;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::process::CommandExt;
+
+    if sockets.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // SD_LISTEN_FDS_START
+    const FIRST_FD: RawFd = 3;
+    let count = RawFd::try_from(sockets.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many sockets"))?;
+
+    // Copy the sockets above the target range first, so moving one into place
+    // can't close another that happens to sit there already
+    let mut staged = Vec::with_capacity(sockets.len());
+    for (_, socket) in sockets {
+        // SAFETY: plain fcntl on a descriptor we own
+        let fd =
+            unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_DUPFD_CLOEXEC, FIRST_FD + count) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fcntl just returned this new descriptor
+        staged.push(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+    let fds: Vec<RawFd> = staged.iter().map(AsRawFd::as_raw_fd).collect();
+
+    let names: Vec<&str> = sockets.iter().map(|(name, _)| name.as_str()).collect();
+    command.env("LISTEN_FDS", count.to_string());
+    // exec keeps our PID
+    command.env("LISTEN_PID", std::process::id().to_string());
+    command.env("LISTEN_FDNAMES", names.join(":"));
+
+    // SAFETY: the closure only calls dup2, which is async-signal-safe. dup2
+    // leaves the new descriptors without FD_CLOEXEC, so they survive exec.
+    unsafe {
+        command.pre_exec(move || {
+            for (target, fd) in (FIRST_FD..).zip(&fds) {
+                if libc::dup2(*fd, target) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(staged)
+}
+
+#[cfg(unix)]
+fn run_command(mut command: Command, program: &OsString) -> ! {
+    use std::os::unix::process::CommandExt;
+
+    // exec only returns on failure
+    let err = command.exec();
+    eprintln!("Error running {}: {}", program.to_string_lossy(), err);
+    std::process::exit(if err.kind() == std::io::ErrorKind::NotFound {
+        127
+    } else {
+        126
+    });
+}
+
+#[cfg(not(unix))]
+fn run_command(mut command: Command, program: &OsString) -> ! {
+    match command.status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(error::EXIT_FAILURE)),
+        Err(e) => {
+            eprintln!("Error running {}: {}", program.to_string_lossy(), e);
+            std::process::exit(if e.kind() == std::io::ErrorKind::NotFound {
+                127
+            } else {
+                126
+            });
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use ports_manager::port_finder::Protocol;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::os::unix::io::AsRawFd;
+
+    /// What `/proc/self/fd/<fd>` links to, e.g. `socket:[12345]`.
+    fn fd_target(pid: &str, fd: i32) -> String {
+        std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd))
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_pass_sockets_to_child() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let sockets = vec![
+            (
+                "web".to_string(),
+                HeldSocket::bind(localhost, 0, Protocol::Tcp)
+                    .unwrap()
+                    .remove(0),
+            ),
+            (
+                "dns".to_string(),
+                HeldSocket::bind(localhost, 0, Protocol::Udp)
+                    .unwrap()
+                    .remove(0),
+            ),
+        ];
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "echo $LISTEN_FDS $LISTEN_PID $LISTEN_FDNAMES; \
+             readlink /proc/$$/fd/3 /proc/$$/fd/4; \
+             test -e /proc/$$/fd/5 && echo leaked || true",
+        ]);
+        let staged = pass_sockets(&mut command, &sockets).unwrap();
+        let output = command.output().unwrap();
+        drop(staged);
+        assert!(output.status.success());
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = stdout.lines().collect();
+        let own = std::process::id().to_string();
+        assert_eq!(lines[0], format!("2 {} web:dns", own));
+        // The same sockets, in order, and nothing else inherited
+        for (line, (_, socket)) in lines[1..].iter().zip(&sockets) {
+            assert_eq!(*line, fd_target("self", socket.as_raw_fd()));
+        }
+        assert_eq!(lines.len(), 3);
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds per unit accepted by [`parse_duration`], largest first.
+const UNITS: [(char, u64); 5] = [
+    ('w', 7 * 24 * 3600),
+    ('d', 24 * 3600),
+    ('h', 3600),
+    ('m', 60),
+    ('s', 1),
+];
+
+/// Current time as Unix seconds, the format of mapping timestamps.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parses a duration like `90`, `30m`, `2h`, `7d` or `1w` into seconds.
+pub fn parse_duration(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let (digits, multiplier) = match input.chars().last() {
+        Some(c) if c.is_ascii_alphabetic() => {
+            let (_, secs) = UNITS
+                .iter()
+                .find(|(unit, _)| *unit == c.to_ascii_lowercase())
+                .ok_or_else(|| {
+                    format!("Invalid duration unit in '{}' (use s, m, h, d or w)", input)
+                })?;
+            (&input[..input.len() - 1], *secs)
+        }
+        _ => (input, 1),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid duration: '{}'", input))?;
+    if value == 0 {
+        return Err(format!("Duration must be positive: '{}'", input));
+    }
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Duration too long: '{}'", input))
+}
+
+/// Formats seconds in the largest unit that divides them, e.g. `2h` or `90m`.
+pub fn format_duration(secs: u64) -> String {
+    UNITS
+        .iter()
+        .find(|(_, unit)| secs > 0 && secs % unit == 0)
+        .map(|(name, unit)| format!("{}{}", secs / unit, name))
+        .unwrap_or_else(|| "0s".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(1800));
+        assert_eq!(parse_duration("2h"), Ok(7200));
+        assert_eq!(parse_duration("7D"), Ok(7 * 86400));
+        assert_eq!(parse_duration("1w"), Ok(7 * 86400));
+        assert!(parse_duration("0h").is_err());
+        assert!(parse_duration("2y").unwrap_err().contains("unit"));
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("-1").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(7200), "2h");
+        assert_eq!(format_duration(5400), "90m");
+        assert_eq!(format_duration(86400 * 14), "2w");
+        assert_eq!(format_duration(61), "61s");
+        assert_eq!(format_duration(0), "0s");
+    }
+}
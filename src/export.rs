@@ -0,0 +1,202 @@
+use crate::config::{PortMapping, PortSpec};
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use std::collections::HashMap;
+
+pub const DEFAULT_SUFFIX: &str = "_PORT";
+
+/// Syntax used when printing environment variable assignments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ShellFormat {
+    /// POSIX shells: `export NAME_PORT=1234`
+    #[default]
+    Sh,
+    /// Plain .env file: `NAME_PORT=1234`
+    Dotenv,
+    /// fish: `set -x NAME_PORT 1234`
+    Fish,
+    /// PowerShell: `$env:NAME_PORT = "1234"`
+    Powershell,
+}
+
+/// Converts a mapping name to SCREAMING_SNAKE_CASE, e.g. `myApp-api.v2` -> `MY_APP_API_V2`.
+pub fn normalize_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut prev: Option<char> = None;
+
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            let word_break = c.is_ascii_uppercase()
+                && prev.is_some_and(|p| p.is_ascii_lowercase() || p.is_ascii_digit());
+            if word_break && !out.ends_with('_') {
+                out.push('_');
+            }
+            out.push(c.to_ascii_uppercase());
+        } else if !out.is_empty() && !out.ends_with('_') {
+            out.push('_');
+        }
+        prev = Some(c);
+    }
+
+    while out.ends_with('_') {
+        out.pop();
+    }
+    if out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    out
+}
+
+/// Environment variables for a mapping: `NAME_PORT` for a single port,
+/// `NAME_PORT_START` and `NAME_PORT_END` for a range.
+pub fn env_vars(mapping: &PortMapping, prefix: &str, suffix: &str) -> Vec<(String, u16)> {
+    let base = format!("{}{}{}", prefix, normalize_name(&mapping.name), suffix);
+    match mapping.port {
+        PortSpec::Single(port) => vec![(base, port)],
+        PortSpec::Range { start, end } => vec![
+            (format!("{}_START", base), start),
+            (format!("{}_END", base), end),
+        ],
+    }
+}
+
+/// [`env_vars`] for each of `mappings`, in order.
+///
+/// A mapping whose name was already seen is skipped, so when the same name
+/// comes from several layers the first one wins. Fails if two different
+/// names would set the same variable, e.g. `web-api` and `web_api`.
+pub fn collect_env_vars(
+    mappings: &[PortMapping],
+    prefix: &str,
+    suffix: &str,
+) -> Result<Vec<(String, u16)>> {
+    let mut vars = Vec::new();
+    let mut set_by: HashMap<String, &str> = HashMap::new();
+    for mapping in mappings {
+        if set_by.values().any(|name| *name == mapping.name) {
+            continue;
+        }
+        for (var, port) in env_vars(mapping, prefix, suffix) {
+            if let Some(other) = set_by.insert(var.clone(), &mapping.name) {
+                return Err(Error::Validation(format!(
+                    "Mappings '{}' and '{}' would both be exported as {}",
+                    other, mapping.name, var
+                )));
+            }
+            vars.push((var, port));
+        }
+    }
+    Ok(vars)
+}
+
+pub fn render(vars: &[(String, u16)], shell: ShellFormat) -> String {
+    let mut out = String::new();
+    for (name, port) in vars {
+        let line = match shell {
+            ShellFormat::Sh => format!("export {}={}", name, port),
+            ShellFormat::Dotenv => format!("{}={}", name, port),
+            ShellFormat::Fish => format!("set -x {} {}", name, port),
+            ShellFormat::Powershell => format!("$env:{} = \"{}\"", name, port),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(name: &str, port: PortSpec) -> PortMapping {
+        PortMapping::new(name, port, None)
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("web"), "WEB");
+        assert_eq!(normalize_name("my-app"), "MY_APP");
+        assert_eq!(normalize_name("myApp"), "MY_APP");
+        assert_eq!(normalize_name("api.v2"), "API_V2");
+        assert_eq!(normalize_name("team/web--api"), "TEAM_WEB_API");
+        assert_eq!(normalize_name("-web-"), "WEB");
+        assert_eq!(normalize_name("HTTP"), "HTTP");
+        assert_eq!(normalize_name("3d-viewer"), "_3D_VIEWER");
+    }
+
+    #[test]
+    fn test_env_vars_single() {
+        let vars = env_vars(&mapping("web", PortSpec::Single(8080)), "", DEFAULT_SUFFIX);
+        assert_eq!(vars, vec![("WEB_PORT".to_string(), 8080)]);
+    }
+
+    #[test]
+    fn test_env_vars_range() {
+        let vars = env_vars(
+            &mapping(
+                "pool",
+                PortSpec::Range {
+                    start: 9000,
+                    end: 9010,
+                },
+            ),
+            "",
+            DEFAULT_SUFFIX,
+        );
+        assert_eq!(
+            vars,
+            vec![
+                ("POOL_PORT_START".to_string(), 9000),
+                ("POOL_PORT_END".to_string(), 9010),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_env_vars_prefix_and_suffix() {
+        let vars = env_vars(
+            &mapping("web", PortSpec::Single(8080)),
+            "DEV_",
+            "_HTTP_PORT",
+        );
+        assert_eq!(vars[0].0, "DEV_WEB_HTTP_PORT");
+    }
+
+    #[test]
+    fn test_collect_env_vars_rejects_collisions() {
+        let mappings = vec![
+            mapping("web-api", PortSpec::Single(8080)),
+            mapping("web_api", PortSpec::Single(8081)),
+        ];
+        let err = collect_env_vars(&mappings, "", DEFAULT_SUFFIX).unwrap_err();
+        assert!(matches!(err, Error::Validation(_)));
+        assert!(err.to_string().contains("WEB_API_PORT"));
+
+        // The same name from a later layer is shadowed, not a collision
+        let mappings = vec![
+            mapping("web", PortSpec::Single(8080)),
+            mapping("api", PortSpec::Single(9000)),
+            mapping("web", PortSpec::Single(3000)),
+        ];
+        let vars = collect_env_vars(&mappings, "", DEFAULT_SUFFIX).unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                ("WEB_PORT".to_string(), 8080),
+                ("API_PORT".to_string(), 9000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_shells() {
+        let vars = vec![("WEB_PORT".to_string(), 8080)];
+        assert_eq!(render(&vars, ShellFormat::Sh), "export WEB_PORT=8080\n");
+        assert_eq!(render(&vars, ShellFormat::Dotenv), "WEB_PORT=8080\n");
+        assert_eq!(render(&vars, ShellFormat::Fish), "set -x WEB_PORT 8080\n");
+        assert_eq!(
+            render(&vars, ShellFormat::Powershell),
+            "$env:WEB_PORT = \"8080\"\n"
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Port mapping configuration and allocation, as used by the `ports-manager` CLI.
+//!
+//! The main entry point is [`Config`], which loads `config.toml` (from the
+//! default location with [`Config::load`], or any file with
+//! [`Config::load_from`]), resolves names, allocates free ports and releases
+//! them again:
+//!
+//! ```no_run
+//! use ports_manager::{AllocationConfig, Config, Protocol};
+//!
+//! let mut config = Config::load_from("ports/config.toml".as_ref())?;
+//! let (web, _) = config.allocate("web", &AllocationConfig::default(), Protocol::Tcp)?;
+//! config.save()?;
+//! println!("web -> {}", web.port.display());
+//!
+//! config.release("web")?;
+//! config.save()?;
+//! # Ok::<(), ports_manager::Error>(())
+//! ```
+//!
+//! Hold a [`lock::ConfigLock`] across load, change and save when other
+//! processes may be editing the same file.
+
+pub mod compose;
+pub mod config;
+pub mod daemon;
+pub mod doctor;
+pub mod error;
+pub mod expiry;
+pub mod export;
+pub mod listeners;
+pub mod lock;
+pub mod output;
+pub mod port_finder;
+pub mod process;
+pub mod project;
+pub mod status;
+pub mod sync;
+
+pub use config::{
+    Allocated, AllocationConfig, AllocationOverrides, Config, DefaultsConfig, MappingSource,
+    PortMapping, PortSpec,
+};
+pub use error::{Error, Result};
+pub use port_finder::{Policy, Protocol};
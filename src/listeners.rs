@@ -0,0 +1,299 @@
+use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+
+/// Transport protocol of a live socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketProtocol {
+    Tcp,
+    Udp,
+}
+
+impl SocketProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SocketProtocol::Tcp => "tcp",
+            SocketProtocol::Udp => "udp",
+        }
+    }
+}
+
+/// A process owning a socket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    pub cmdline: String,
+}
+
+/// A listening TCP socket or unconnected UDP socket.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Listener {
+    pub protocol: SocketProtocol,
+    pub address: SocketAddr,
+    pub uid: u32,
+    pub inode: u64,
+    /// `None` when the owning process isn't visible to us, usually because it
+    /// belongs to another user and we aren't root
+    pub process: Option<ProcessInfo>,
+}
+
+impl Listener {
+    pub fn port(&self) -> u16 {
+        self.address.port()
+    }
+}
+
+/// Every listening TCP socket and unconnected UDP socket on the host, with the
+/// owning process where it can be determined.
+#[cfg(target_os = "linux")]
+pub fn all_listeners() -> io::Result<Vec<Listener>> {
+    use std::fs;
+
+    let tables = [
+        ("/proc/net/tcp", SocketProtocol::Tcp),
+        ("/proc/net/tcp6", SocketProtocol::Tcp),
+        ("/proc/net/udp", SocketProtocol::Udp),
+        ("/proc/net/udp6", SocketProtocol::Udp),
+    ];
+    let mut listeners = Vec::new();
+    let mut read_any = false;
+    for (path, protocol) in tables {
+        // tcp6/udp6 are missing when IPv6 is disabled
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                read_any = true;
+                listeners.extend(linux::parse_table(&content, protocol));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if !read_any {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "/proc/net is not available",
+        ));
+    }
+
+    let owners = linux::socket_owners();
+    for listener in &mut listeners {
+        listener.process = owners.get(&listener.inode).cloned();
+    }
+    Ok(listeners)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn all_listeners() -> io::Result<Vec<Listener>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "inspecting listeners requires /proc/net and is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{Listener, ProcessInfo, SocketProtocol};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    /// `st` value of a TCP socket in the LISTEN state
+    const TCP_LISTEN: &str = "0A";
+
+    /// Parses a `/proc/net/{tcp,udp}{,6}` table, keeping listening TCP sockets
+    /// and unconnected UDP sockets. Malformed lines are skipped.
+    pub fn parse_table(content: &str, protocol: SocketProtocol) -> Vec<Listener> {
+        content
+            .lines()
+            .skip(1)
+            .filter_map(|line| parse_line(line, protocol))
+            .collect()
+    }
+
+    fn parse_line(line: &str, protocol: SocketProtocol) -> Option<Listener> {
+        // sl local_address rem_address st tx:rx tr:when retrnsmt uid timeout inode
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 {
+            return None;
+        }
+        if protocol == SocketProtocol::Tcp && fields[3] != TCP_LISTEN {
+            return None;
+        }
+        // A UDP socket with a remote address is a connected client, not a server
+        if protocol == SocketProtocol::Udp && parse_address(fields[2])?.port() != 0 {
+            return None;
+        }
+        Some(Listener {
+            protocol,
+            address: parse_address(fields[1])?,
+            uid: fields[7].parse().ok()?,
+            inode: fields[9].parse().ok()?,
+            process: None,
+        })
+    }
+
+    /// Parses `0100007F:1F90` or the 32-digit IPv6 form. The address is the
+    /// kernel's in-memory network-order bytes printed as native-endian words;
+    /// the port is plain host-order hex.
+    pub fn parse_address(field: &str) -> Option<SocketAddr> {
+        let (addr, port) = field.split_once(':')?;
+        let port = u16::from_str_radix(port, 16).ok()?;
+        let words = (0..addr.len() / 8)
+            .map(|i| u32::from_str_radix(addr.get(i * 8..i * 8 + 8)?, 16).ok())
+            .collect::<Option<Vec<u32>>>()?;
+
+        let ip = match words.as_slice() {
+            [word] if addr.len() == 8 => IpAddr::V4(Ipv4Addr::from(word.to_ne_bytes())),
+            [a, b, c, d] if addr.len() == 32 => {
+                let mut bytes = [0u8; 16];
+                for (chunk, word) in bytes.chunks_mut(4).zip([a, b, c, d]) {
+                    chunk.copy_from_slice(&word.to_ne_bytes());
+                }
+                IpAddr::V6(Ipv6Addr::from(bytes))
+            }
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, port))
+    }
+
+    /// Maps socket inodes to the processes holding them by scanning
+    /// `/proc/*/fd`. Processes we may not inspect are silently skipped.
+    pub fn socket_owners() -> HashMap<u64, ProcessInfo> {
+        let mut owners = HashMap::new();
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return owners;
+        };
+        for entry in entries.flatten() {
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let Ok(fds) = fs::read_dir(entry.path().join("fd")) else {
+                continue;
+            };
+            let mut process = None;
+            for fd in fds.flatten() {
+                let Some(inode) = fs::read_link(fd.path())
+                    .ok()
+                    .and_then(|target| socket_inode(&target.to_string_lossy()))
+                else {
+                    continue;
+                };
+                let info = process.get_or_insert_with(|| process_info(pid));
+                owners.entry(inode).or_insert_with(|| info.clone());
+            }
+        }
+        owners
+    }
+
+    /// Extracts the inode from an fd link target like `socket:[12345]`.
+    pub fn socket_inode(target: &str) -> Option<u64> {
+        target
+            .strip_prefix("socket:[")?
+            .strip_suffix(']')?
+            .parse()
+            .ok()
+    }
+
+    fn process_info(pid: u32) -> ProcessInfo {
+        let dir = format!("/proc/{}", pid);
+        let name = fs::read_to_string(format!("{}/comm", dir))
+            .map(|s| s.trim_end().to_string())
+            .unwrap_or_default();
+        let cmdline = fs::read(format!("{}/cmdline", dir))
+            .map(|raw| {
+                raw.split(|&b| b == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .unwrap_or_default();
+        ProcessInfo { pid, name, cmdline }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::{TcpListener, UdpSocket};
+
+        const TCP_TABLE: &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 4242 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:1F91 0100007F:A000 01 00000000:00000000 00:00000000 00000000  1000        0 4243 1 0000000000000000 100 0 0 10 0
+   2: garbage
+";
+
+        #[test]
+        fn test_parse_table_keeps_only_listening_tcp() {
+            let listeners = parse_table(TCP_TABLE, SocketProtocol::Tcp);
+            assert_eq!(listeners.len(), 1);
+            assert_eq!(listeners[0].address, "127.0.0.1:8080".parse().unwrap());
+            assert_eq!(listeners[0].uid, 1000);
+            assert_eq!(listeners[0].inode, 4242);
+        }
+
+        const UDP_TABLE: &str = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  100: 00000000:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000   101        0 5151 2 0000000000000000 0
+  101: 0100007F:C350 0100007F:0035 01 00000000:00000000 00:00000000 00000000  1000        0 5152 2 0000000000000000 0
+";
+
+        #[test]
+        fn test_parse_table_skips_connected_udp() {
+            let listeners = parse_table(UDP_TABLE, SocketProtocol::Udp);
+            assert_eq!(listeners.len(), 1);
+            assert_eq!(listeners[0].address, "0.0.0.0:53".parse().unwrap());
+            assert_eq!(listeners[0].inode, 5151);
+        }
+
+        #[test]
+        fn test_parse_address_ipv6() {
+            let loopback = parse_address("00000000000000000000000001000000:0035").unwrap();
+            assert_eq!(loopback, "[::1]:53".parse().unwrap());
+            let any = parse_address("00000000000000000000000000000000:1F90").unwrap();
+            assert_eq!(any, "[::]:8080".parse().unwrap());
+            assert!(parse_address("0100007F").is_none());
+            assert!(parse_address("01007F:1F90").is_none());
+        }
+
+        #[test]
+        fn test_socket_inode() {
+            assert_eq!(socket_inode("socket:[12345]"), Some(12345));
+            assert_eq!(socket_inode("pipe:[12345]"), None);
+            assert_eq!(socket_inode("/dev/null"), None);
+        }
+
+        #[test]
+        fn test_all_listeners_finds_own_sockets() {
+            let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = tcp.local_addr().unwrap().port();
+            let udp = UdpSocket::bind(("127.0.0.1", port)).ok();
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            client.connect(("127.0.0.1", port)).unwrap();
+            let client_port = client.local_addr().unwrap().port();
+
+            let found: Vec<Listener> = super::super::all_listeners()
+                .unwrap()
+                .into_iter()
+                .filter(|l| l.port() == port)
+                .collect();
+            let ours = found
+                .iter()
+                .find(|l| l.protocol == SocketProtocol::Tcp)
+                .expect("TCP listener not found");
+            let process = ours.process.as_ref().expect("own process not visible");
+            assert_eq!(process.pid, std::process::id());
+            if udp.is_some() {
+                assert!(found.iter().any(|l| l.protocol == SocketProtocol::Udp));
+            }
+            assert!(!super::super::all_listeners()
+                .unwrap()
+                .iter()
+                .any(|l| l.protocol == SocketProtocol::Udp && l.port() == client_port));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub(crate) const LOCK_FILE: &str = ".lock";
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Advisory lock on the config directory, held for a whole read-modify-write cycle.
+///
+/// The lock is released when the value is dropped.
+pub struct ConfigLock {
+    file: File,
+}
+
+impl ConfigLock {
+    pub fn acquire(timeout: Duration) -> Result<Self> {
+        let config_dir = Config::config_dir()?;
+        fs::create_dir_all(&config_dir).map_err(|e| Error::io(&config_dir, e))?;
+        Self::acquire_at(&config_dir.join(LOCK_FILE), timeout)
+    }
+
+    pub fn acquire_at(path: &Path, timeout: Duration) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| Error::io(path, e))?;
+
+        // A timeout too large to represent means waiting indefinitely
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(ConfigLock { file }),
+                Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(Error::LockTimeout {
+                            path: path.to_path_buf(),
+                            timeout,
+                        });
+                    }
+                    thread::sleep(RETRY_INTERVAL);
+                }
+                Err(e) => return Err(Error::io(path, e)),
+            }
+        }
+    }
+}
+
+impl Drop for ConfigLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn lock_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "ports-manager-lock-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_lock_acquire_and_release() {
+        let path = lock_path("release");
+        let lock = ConfigLock::acquire_at(&path, Duration::from_millis(100)).unwrap();
+        drop(lock);
+
+        let relock = ConfigLock::acquire_at(&path, Duration::from_millis(100));
+        assert!(relock.is_ok());
+        drop(relock);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_lock_times_out_when_held() {
+        let path = lock_path("timeout");
+        let _held = ConfigLock::acquire_at(&path, Duration::from_millis(100)).unwrap();
+
+        let started = Instant::now();
+        let result = ConfigLock::acquire_at(&path, Duration::from_millis(200));
+        assert!(result.is_err());
+        assert!(result.err().unwrap().to_string().contains("Timed out"));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_lock_with_unbounded_timeout() {
+        let path = lock_path("unbounded");
+        let held = ConfigLock::acquire_at(&path, Duration::MAX).unwrap();
+
+        // Waiting with a timeout past what Instant can represent must not overflow
+        let waiter_path = path.clone();
+        let waiter =
+            thread::spawn(move || ConfigLock::acquire_at(&waiter_path, Duration::MAX).is_ok());
+        thread::sleep(Duration::from_millis(100));
+        drop(held);
+
+        assert!(waiter.join().unwrap());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_lock_waits_for_release() {
+        let path = lock_path("wait");
+        let held = ConfigLock::acquire_at(&path, Duration::from_millis(100)).unwrap();
+
+        let waiter_path = path.clone();
+        let waiter = thread::spawn(move || {
+            ConfigLock::acquire_at(&waiter_path, Duration::from_secs(5)).is_ok()
+        });
+        thread::sleep(Duration::from_millis(100));
+        drop(held);
+
+        assert!(waiter.join().unwrap());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,17 +1,90 @@
-mod config;
-mod port_finder;
+mod exec;
 
-use clap::{Parser, Subcommand};
-use config::{Config, DefaultsConfig, PortSpec};
+use clap::{Args, Parser, Subcommand};
+use ports_manager::compose;
+use ports_manager::config::{
+    Allocated, AllocationConfig, AllocationOverrides, Config, DefaultsConfig, MappingSource,
+    PortMapping, PortSpec, CONFIG_DIR_ENV,
+};
+use ports_manager::daemon::{self, Namespace, Request};
+use ports_manager::doctor::{self, Severity};
+use ports_manager::export::{self, ShellFormat};
+use ports_manager::lock::{self, ConfigLock};
+use ports_manager::output::{self, OutputFormat, PortRecord};
+use ports_manager::port_finder::{self, HeldSocket, Policy, Protocol};
+use ports_manager::process::Owner;
+use ports_manager::status::{self, ColorChoice, StatusFormat};
+use ports_manager::{error, expiry, listeners, project, Error};
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "ports-manager")]
 #[command(about = "A simple port management tool", long_about = None)]
 struct Cli {
+    /// Seconds to wait for another ports-manager process to release the config lock
+    #[arg(long, global = true, default_value_t = lock::DEFAULT_LOCK_TIMEOUT.as_secs_f64())]
+    lock_timeout: f64,
+    /// Use the global namespace even inside a project
+    #[arg(long, global = true, conflicts_with = "project")]
+    global: bool,
+    /// Use this project namespace instead of the one discovered from the current directory
+    #[arg(long, global = true, value_name = "NAME")]
+    project: Option<String>,
+    /// Directory holding config.toml and defaults.toml [env: PORTS_MANAGER_CONFIG_DIR]
+    #[arg(long, global = true, value_name = "DIR")]
+    config_dir: Option<PathBuf>,
+    /// Read and write the config files directly even when a daemon is running
+    #[arg(long, global = true)]
+    no_daemon: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Per-call overrides for the `[allocation]` settings in config.toml
+#[derive(Args)]
+struct AllocationArgs {
+    /// Range to auto-assign from (e.g., 20000-29999)
+    #[arg(long, value_name = "RANGE")]
+    range: Option<String>,
+    /// Where in the range to look for a free port
+    #[arg(long, value_enum)]
+    policy: Option<Policy>,
+    /// Never auto-assign this port or range (repeatable)
+    #[arg(long, value_name = "PORT")]
+    exclude: Vec<String>,
+    /// Skip the OS ephemeral port range
+    #[arg(long)]
+    exclude_ephemeral: bool,
+    /// Protocol a newly assigned port must be free for
+    #[arg(long, value_enum, default_value_t)]
+    protocol: Protocol,
+    /// Let `gc` remove the auto-assigned mapping once unused this long (e.g. 30m, 2h, 7d)
+    #[arg(long, value_name = "DURATION")]
+    ttl: Option<String>,
+    /// Release the auto-assigned mapping once the process with this PID exits (Linux)
+    #[arg(long, value_name = "PID")]
+    bind_pid: Option<u32>,
+}
+
+impl AllocationArgs {
+    /// This call's overrides, to apply to the configured settings.
+    fn overrides(&self) -> Result<AllocationOverrides, String> {
+        Ok(AllocationOverrides {
+            range: self.range.as_deref().map(PortSpec::parse).transpose()?,
+            policy: self.policy,
+            exclude: self
+                .exclude
+                .iter()
+                .map(|exclude| PortSpec::parse(exclude))
+                .collect::<Result<_, _>>()?,
+            exclude_ephemeral: self.exclude_ephemeral,
+        })
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Set a port mapping
@@ -23,11 +96,47 @@ enum Commands {
         /// Optional description
         #[arg(short, long)]
         description: Option<String>,
+        /// Protocol the service listens on [default: tcp, or unchanged when updating]
+        #[arg(long, value_enum)]
+        protocol: Option<Protocol>,
+        /// Set the port even if it overlaps other mappings or defaults
+        #[arg(long)]
+        force: bool,
+        /// Mark the port as deliberately shared, so overlaps aren't conflicts
+        #[arg(long)]
+        allow_shared: bool,
+        /// Clear a previous --allow-shared, so overlaps are conflicts again
+        #[arg(long, conflicts_with = "allow_shared")]
+        no_shared: bool,
     },
     /// Get a port value (outputs just the port for shell capture)
     Get {
         /// Name of the service/application
         name: String,
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// Allocate N consecutive ports as a range; text output lists every port
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+        block: Option<u16>,
+        #[command(flatten)]
+        allocation: AllocationArgs,
+    },
+    /// Remove auto-assigned mappings that are past their TTL or long unused
+    Gc {
+        /// Also remove auto-assigned mappings unused for longer than this (e.g. 30d)
+        #[arg(long, value_name = "DURATION")]
+        unused_for: Option<String>,
+        /// Also remove mappings with no usage record: ones made or pinned with `set`, and
+        /// ones auto-assigned before usage was tracked (check with --dry-run first)
+        #[arg(long)]
+        include_unstamped: bool,
+        /// Keep mappings whose port is currently in use
+        #[arg(long)]
+        skip_in_use: bool,
+        /// Show what would be removed without changing anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Remove a port mapping
     Remove {
@@ -35,123 +144,1289 @@ enum Commands {
         name: String,
     },
     /// List all port mappings
-    List,
+    List {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t)]
+        format: OutputFormat,
+        /// Also include defaults from defaults.toml that are in effect
+        #[arg(short, long)]
+        all: bool,
+        /// Show mappings from every project namespace
+        #[arg(long, conflicts_with_all = ["project", "global"])]
+        all_projects: bool,
+    },
+    /// Show whether each mapped port is free or in use, and by what
+    Status {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t)]
+        format: StatusFormat,
+        /// When to color the table
+        #[arg(long, value_enum, default_value_t)]
+        color: ColorChoice,
+    },
+    /// Show which processes are listening on a mapping's port(s)
+    Who {
+        /// Mapping name or port number
+        target: String,
+    },
+    /// Print NAME_PORT environment variable assignments for port mappings
+    Export {
+        /// Mappings to export (defaults to all user mappings)
+        names: Vec<String>,
+        /// Syntax of the emitted assignments
+        #[arg(short, long, value_enum, default_value_t)]
+        shell: ShellFormat,
+        /// Prefix prepended to every variable name
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// Suffix appended to every variable name
+        #[arg(long, default_value = export::DEFAULT_SUFFIX)]
+        suffix: String,
+        /// Also export defaults that are in effect when no names are given
+        #[arg(short, long)]
+        all: bool,
+    },
+    /// Run a command with allocated ports injected into its environment
+    Exec {
+        /// Mapping to resolve or auto-assign, exported as NAME_PORT (repeatable)
+        #[arg(short, long = "port", value_name = "NAME", required = true)]
+        ports: Vec<String>,
+        /// Also set PORT to the first mapping's port
+        #[arg(long)]
+        set_port: bool,
+        /// Bind the ports and pass the open sockets to the command as fds 3, 4, ...
+        /// with systemd's LISTEN_FDS, so nothing can take them first (Unix)
+        #[arg(long)]
+        pass_sockets: bool,
+        /// Address to bind passed sockets to
+        #[arg(long, value_name = "ADDR", default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST), requires = "pass_sockets")]
+        listen_address: IpAddr,
+        #[command(flatten)]
+        allocation: AllocationArgs,
+        /// Command to run, followed by its arguments
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<OsString>,
+    },
+    /// Allocate host ports for a compose file's published ports and write an override file
+    Compose {
+        /// Compose file [default: compose.yaml, docker-compose.yml, ... in the current directory]
+        #[arg(short, long, value_name = "FILE")]
+        file: Option<PathBuf>,
+        /// Override file to write [default: <compose file>.override.yml next to it]
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+        /// Print the override instead of writing it (ports are still allocated)
+        #[arg(long, conflicts_with = "output")]
+        stdout: bool,
+        /// Rewrite an override file that was edited by hand, dropping its comments and formatting
+        #[arg(long)]
+        force: bool,
+        #[command(flatten)]
+        allocation: AllocationArgs,
+    },
     /// Reset defaults.toml to built-in default port mappings
     ResetDefaults,
-    /// Sync defaults.toml with newer versions (preserves ignored entries)
-    SyncDefaults,
+    /// Merge newer built-in defaults into defaults.toml, keeping local edits
+    SyncDefaults {
+        /// Show what would change without writing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Restore config.toml or defaults.toml from its automatic backup if it is unreadable
+    RestoreBackup,
+    /// Check the configuration for conflicts and problems (exits 1 on errors)
+    Doctor {
+        /// Also exit 1 on warnings
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Serve get, remove, exec and list for other invocations over a Unix socket
+    Daemon {
+        /// Stop the running daemon instead
+        #[arg(long)]
+        stop: bool,
+    },
+}
+
+impl Commands {
+    /// Whether the command rewrites config files and must hold the config lock.
+    fn modifies_config(&self) -> bool {
+        !matches!(
+            self,
+            Commands::List { .. }
+                | Commands::Export { .. }
+                | Commands::Status { .. }
+                | Commands::Who { .. }
+                | Commands::Doctor { .. }
+                | Commands::Daemon { .. }
+        )
+    }
+
+    /// Whether the command goes through the daemon when one is running.
+    fn uses_daemon(&self) -> bool {
+        matches!(
+            self,
+            Commands::Get { .. }
+                | Commands::Remove { .. }
+                | Commands::Exec { .. }
+                | Commands::List { .. }
+        )
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
 
-    let mut config = match Config::load() {
+    // Through the environment so the lock, every file and commands run by
+    // `exec` all agree on the location
+    if let Some(dir) = &cli.config_dir {
+        std::env::set_var(CONFIG_DIR_ENV, dir);
+    }
+
+    if let Commands::Daemon { stop } = cli.command {
+        run_daemon(stop, lock_timeout(&cli));
+        return;
+    }
+
+    // The daemon takes the lock itself, so this must come before acquiring it
+    if cli.command.uses_daemon() && !cli.no_daemon {
+        if let Some(client) = connect_daemon() {
+            let (scope, project) = namespace(&cli);
+            let namespace = Namespace {
+                scope,
+                project_root: project.map(|p| p.root),
+            };
+            with_daemon(client, cli.command, &namespace);
+            return;
+        }
+    }
+
+    // Hold the lock across load/modify/save so concurrent invocations can't
+    // hand out the same port or overwrite each other's changes.
+    let lock = if cli.command.modifies_config() {
+        match ConfigLock::acquire(lock_timeout(&cli)) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                fail("Error acquiring config lock", e);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Restoring must not depend on loading the (possibly corrupt) config first
+    if let Commands::RestoreBackup = cli.command {
+        match Config::restore_backups() {
+            Ok(restored) if restored.is_empty() => {
+                eprintln!("Nothing to restore: no unreadable file with a valid backup");
+                std::process::exit(error::EXIT_NOT_FOUND);
+            }
+            Ok(restored) => {
+                for path in restored {
+                    eprintln!("Restored {} from backup", path.display());
+                }
+            }
+            Err(e) => {
+                fail("Error restoring backups", e);
+            }
+        }
+        return;
+    }
+
+    // Doctor reports unreadable files instead of failing to load them
+    if let Commands::Doctor { strict } = cli.command {
+        let (scope, project) = namespace(&cli);
+        let findings = doctor::run(
+            scope.as_deref(),
+            project.as_ref(),
+            port_finder::ephemeral_port_range(),
+        );
+        for finding in &findings {
+            println!("{}: {}", finding.severity, finding.message);
+        }
+        let count = |severity| findings.iter().filter(|f| f.severity == severity).count();
+        let (errors, warnings) = (count(Severity::Error), count(Severity::Warning));
+        if errors == 0 && warnings == 0 {
+            println!("No problems found");
+        } else {
+            println!("{} error(s), {} warning(s)", errors, warnings);
+        }
+        if errors > 0 || (strict && warnings > 0) {
+            std::process::exit(error::EXIT_FAILURE);
+        }
+        return;
+    }
+
+    let mut config = match Config::load(lock.as_ref()) {
         Ok(cfg) => cfg,
         Err(e) => {
-            eprintln!("Error loading config: {}", e);
-            std::process::exit(1);
+            fail("Error loading config", e);
         }
     };
 
+    let (scope, project) = namespace(&cli);
+    if let Some(project) = project {
+        match project.load_file() {
+            Ok(file) => config.project_file = file,
+            Err(e) => {
+                fail("Error loading project file", e);
+            }
+        }
+    }
+    config.scope = scope;
+
+    // Ports of bound processes that have exited go back before allocating
+    if lock.is_some() && cli.command.uses_daemon() && !config.release_orphans().is_empty() {
+        if let Err(e) = config.save() {
+            fail("Error saving config", e);
+        }
+    }
+
     match cli.command {
         Commands::Set {
             name,
             port,
             description,
+            protocol,
+            force,
+            allow_shared,
+            no_shared,
         } => {
             let port_spec = match PortSpec::parse(&port) {
                 Ok(spec) => spec,
                 Err(e) => {
                     eprintln!("Error parsing port: {}", e);
-                    std::process::exit(1);
+                    std::process::exit(error::EXIT_INVALID);
                 }
             };
-            config.add_port(name.clone(), port_spec.clone(), description);
+            let defaults = match DefaultsConfig::load(lock.as_ref()) {
+                Ok(defaults) => defaults,
+                Err(e) => {
+                    fail("Error loading defaults", e);
+                }
+            };
+
+            let (previous, mapping) = config.upsert_port(&name, port_spec);
+            if description.is_some() {
+                mapping.description = description;
+            }
+            if let Some(protocol) = protocol {
+                mapping.protocol = protocol;
+            }
+            if allow_shared {
+                mapping.shared = true;
+            } else if no_shared {
+                mapping.shared = false;
+            }
+            let mapping = mapping.clone();
+
+            let conflicts = config.conflicts(&mapping, &defaults);
+            for (other, source) in &conflicts {
+                let source = match source {
+                    MappingSource::User => "mapping",
+                    MappingSource::Project => "project port",
+                    MappingSource::System => "system mapping",
+                    MappingSource::Default => "default",
+                };
+                eprintln!(
+                    "{} overlaps {} '{}' ({})",
+                    mapping.port.display(),
+                    source,
+                    other.qualified_name(),
+                    other.port.display()
+                );
+            }
+            if !conflicts.is_empty() && !force {
+                eprintln!(
+                    "Refusing to set {}; use --allow-shared if the port is deliberately shared, or --force",
+                    mapping.qualified_name()
+                );
+                std::process::exit(error::EXIT_INVALID);
+            }
+
             if let Err(e) = config.save() {
-                eprintln!("Error saving config: {}", e);
-                std::process::exit(1);
+                fail("Error saving config", e);
+            }
+            match previous {
+                None => eprintln!(
+                    "Set port mapping: {} -> {}",
+                    mapping.qualified_name(),
+                    mapping.port.display()
+                ),
+                Some(previous) => {
+                    let changes = describe_changes(&previous, &mapping);
+                    if changes.is_empty() {
+                        eprintln!(
+                            "Port mapping unchanged: {} -> {}",
+                            mapping.qualified_name(),
+                            mapping.port.display()
+                        );
+                    } else {
+                        eprintln!(
+                            "Updated port mapping {}: {}",
+                            mapping.qualified_name(),
+                            changes.join(", ")
+                        );
+                    }
+                }
             }
-            eprintln!("Set port mapping: {} -> {}", name, port_spec.display());
         }
-        Commands::Get { name } => {
-            if let Some(mapping) = config.find_port(&name) {
-                println!("{}", mapping.port.display());
-            } else {
-                // Port not found - auto-assign a new one
-                let used_ports = config.get_used_ports();
-                match port_finder::find_available_port(&used_ports) {
-                    Some(port) => {
-                        let port_spec = PortSpec::Single(port);
-                        config.add_port(name.clone(), port_spec.clone(), None);
-                        if let Err(e) = config.save() {
-                            eprintln!("Error saving config: {}", e);
-                            std::process::exit(1);
-                        }
-                        eprintln!("Auto-assigned port for '{}': {}", name, port);
-                        println!("{}", port);
+        Commands::Get {
+            name,
+            format,
+            block,
+            allocation,
+        } => {
+            let settings = allocation_settings(&config, &allocation);
+            let lifetime = allocation_lifetime(&allocation);
+            let (mapping, source) = match block {
+                Some(count) => get_or_assign_block(
+                    &mut config,
+                    &name,
+                    count,
+                    &settings,
+                    allocation.protocol,
+                    lifetime,
+                ),
+                None => get_or_assign(&mut config, &name, &settings, allocation.protocol, lifetime),
+            };
+            print_allocation(&mapping, source, format, block.is_some());
+        }
+        Commands::Gc {
+            unused_for,
+            include_unstamped,
+            skip_in_use,
+            dry_run,
+        } => {
+            let max_idle = unused_for.map(|d| match expiry::parse_duration(&d) {
+                Ok(secs) => secs,
+                Err(e) => {
+                    eprintln!("Error parsing --unused-for: {}", e);
+                    std::process::exit(error::EXIT_INVALID);
+                }
+            });
+            let now = expiry::now();
+            let mut candidates: Vec<(PortMapping, String)> = config
+                .orphans()
+                .into_iter()
+                .map(|mapping| {
+                    let pid = mapping.owner.map_or(0, |owner| owner.pid);
+                    (mapping, format!("owner process {} exited", pid))
+                })
+                .collect();
+            for mapping in config.stale_ports(now, max_idle) {
+                if candidates.iter().any(|(orphan, _)| *orphan == mapping) {
+                    continue;
+                }
+                let idle = now.saturating_sub(mapping.last_used.or(mapping.created).unwrap_or(now));
+                let reason = format!("unused for {}", expiry::format_duration(idle));
+                candidates.push((mapping, reason));
+            }
+            if include_unstamped {
+                candidates.extend(
+                    config
+                        .unstamped_ports()
+                        .into_iter()
+                        .map(|mapping| (mapping, "no usage recorded".to_string())),
+                );
+            }
+            let mut removed = 0;
+            for (mapping, reason) in candidates {
+                let label = format!(
+                    "{} ({}), {}",
+                    mapping.qualified_name(),
+                    mapping.port.display(),
+                    reason
+                );
+                let (start, end) = mapping.port.bounds();
+                if skip_in_use
+                    && (start..=end)
+                        .any(|port| !port_finder::is_port_available_for(port, mapping.protocol))
+                {
+                    eprintln!("Kept {}: port in use", label);
+                    continue;
+                }
+                if dry_run {
+                    println!("Would remove {}", label);
+                } else {
+                    config.remove_mapping(&mapping);
+                    println!("Removed {}", label);
+                }
+                removed += 1;
+            }
+            if removed == 0 {
+                eprintln!("No stale mappings");
+            } else if !dry_run {
+                if let Err(e) = config.save() {
+                    fail("Error saving config", e);
+                }
+            }
+        }
+        Commands::Remove { name } => {
+            let pool = config.pool_of(&name);
+            let removed = match config.release(&name) {
+                Ok(removed) => removed,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(e.exit_code());
+                }
+            };
+            if let Err(e) = config.save() {
+                fail("Error saving config", e);
+            }
+            report_removal(&removed, pool.map(|pool| pool.name).as_deref());
+        }
+        Commands::Status { format, color } => {
+            let mut mappings: Vec<(PortMapping, MappingSource)> = config
+                .project_file_ports()
+                .into_iter()
+                .map(|m| (m, MappingSource::Project))
+                .collect();
+            mappings.extend(
+                config
+                    .list_ports()
+                    .iter()
+                    .map(|m| (m.clone(), MappingSource::User)),
+            );
+            mappings.extend(
+                config
+                    .system_ports()
+                    .into_iter()
+                    .map(|m| (m, MappingSource::System)),
+            );
+            match DefaultsConfig::load(lock.as_ref()) {
+                Ok(defaults) => mappings.extend(
+                    config
+                        .effective_defaults(&defaults)
+                        .into_iter()
+                        .map(|m| (m, MappingSource::Default)),
+                ),
+                Err(e) => {
+                    fail("Error loading defaults", e);
+                }
+            }
+
+            // Without /proc we can still tell free from busy by probing
+            let listeners = listeners::all_listeners().ok();
+            let statuses: Vec<_> = mappings
+                .iter()
+                .map(|(mapping, source)| status::check(mapping, *source, listeners.as_deref()))
+                .collect();
+
+            match format {
+                StatusFormat::Json => print_rendered(
+                    serde_json::to_string_pretty(&statuses)
+                        .map(|json| json + "\n")
+                        .map_err(Into::into),
+                ),
+                StatusFormat::Text if statuses.is_empty() => {
+                    println!("No port mappings configured")
+                }
+                StatusFormat::Text => print!("{}", status::render_text(&statuses, color.enabled())),
+            }
+        }
+        Commands::Who { target } => {
+            let (label, ports) = match target.parse::<u16>() {
+                Ok(port) => (format!("port {}", port), vec![port]),
+                Err(_) => match config.find_port(&target) {
+                    Some(mapping) => {
+                        let ports: Vec<u16> = match mapping.port {
+                            PortSpec::Single(port) => vec![port],
+                            PortSpec::Range { start, end } => (start..=end).collect(),
+                        };
+                        (
+                            format!("{} ({})", mapping.qualified_name(), mapping.port.display()),
+                            ports,
+                        )
                     }
                     None => {
-                        eprintln!("No available ports found");
-                        std::process::exit(1);
+                        eprintln!("Port mapping not found: {}", target);
+                        std::process::exit(error::EXIT_NOT_FOUND);
+                    }
+                },
+            };
+
+            let listeners = match listeners::all_listeners() {
+                Ok(all) => all,
+                Err(e) => {
+                    eprintln!("Error inspecting listeners: {}", e);
+                    std::process::exit(error::EXIT_IO);
+                }
+            };
+            let matching: Vec<_> = listeners
+                .iter()
+                .filter(|l| ports.contains(&l.port()))
+                .collect();
+            if matching.is_empty() {
+                eprintln!("Nothing is listening on {}", label);
+                return;
+            }
+
+            println!(
+                "{:<6} {:<28} {:<8} {:<16} Command",
+                "Proto", "Address", "PID", "Process"
+            );
+            println!("{}", "-".repeat(80));
+            for listener in matching {
+                let address = listener.address.to_string();
+                match &listener.process {
+                    Some(process) => println!(
+                        "{:<6} {:<28} {:<8} {:<16} {}",
+                        listener.protocol.as_str(),
+                        address,
+                        process.pid,
+                        process.name,
+                        process.cmdline
+                    ),
+                    None => println!(
+                        "{:<6} {:<28} {:<8} {:<16} (owned by uid {}; run as root to see the process)",
+                        listener.protocol.as_str(),
+                        address,
+                        "-",
+                        "?",
+                        listener.uid
+                    ),
+                }
+            }
+        }
+        Commands::List {
+            format,
+            all,
+            all_projects,
+        } => {
+            let ports = if all_projects {
+                config.list_ports().to_vec()
+            } else {
+                config.visible_ports()
+            };
+            let mut records: Vec<PortRecord> = config
+                .project_file_ports()
+                .iter()
+                .map(|m| PortRecord::new(m, MappingSource::Project))
+                .collect();
+            records.extend(
+                ports
+                    .iter()
+                    .map(|m| PortRecord::new(m, MappingSource::User)),
+            );
+            records.extend(
+                config
+                    .system_ports()
+                    .iter()
+                    .map(|m| PortRecord::new(m, MappingSource::System)),
+            );
+            if all {
+                match DefaultsConfig::load(lock.as_ref()) {
+                    Ok(defaults) => records.extend(
+                        config
+                            .effective_defaults(&defaults)
+                            .iter()
+                            .map(|m| PortRecord::new(m, MappingSource::Default)),
+                    ),
+                    Err(e) => {
+                        fail("Error loading defaults", e);
                     }
                 }
             }
+
+            let leased: Vec<usize> = records
+                .iter()
+                .map(|record| match (record.start, record.end) {
+                    (Some(start), Some(end)) => {
+                        let pool =
+                            PortMapping::new(&record.name, PortSpec::Range { start, end }, None);
+                        config.pool_leases(&pool).len()
+                    }
+                    _ => 0,
+                })
+                .collect();
+            print_list(&records, &leased, format);
         }
-        Commands::Remove { name } => {
-            if config.remove_port(&name) {
-                if let Err(e) = config.save() {
-                    eprintln!("Error saving config: {}", e);
-                    std::process::exit(1);
+        Commands::Export {
+            names,
+            shell,
+            prefix,
+            suffix,
+            all,
+        } => {
+            let mut mappings = Vec::new();
+            if names.is_empty() {
+                mappings.extend(config.project_file_ports());
+                mappings.extend(config.visible_ports());
+                mappings.extend(config.system_ports());
+                if all {
+                    match DefaultsConfig::load(lock.as_ref()) {
+                        Ok(defaults) => mappings.extend(config.effective_defaults(&defaults)),
+                        Err(e) => {
+                            fail("Error loading defaults", e);
+                        }
+                    }
                 }
-                eprintln!("Removed port mapping: {}", name);
             } else {
-                eprintln!("Port mapping not found: {}", name);
-                std::process::exit(1);
+                for name in &names {
+                    match config.find_port(name) {
+                        Some(mapping) => mappings.push(mapping),
+                        None => {
+                            eprintln!("Port mapping not found: {}", name);
+                            std::process::exit(error::EXIT_NOT_FOUND);
+                        }
+                    }
+                }
             }
+
+            let vars = match export::collect_env_vars(&mappings, &prefix, &suffix) {
+                Ok(vars) => vars,
+                Err(e) => {
+                    fail("Error exporting ports", e);
+                }
+            };
+            print!("{}", export::render(&vars, shell));
         }
-        Commands::List => {
-            let ports = config.list_ports();
-            if ports.is_empty() {
-                println!("No port mappings configured");
+        Commands::Exec {
+            ports,
+            set_port,
+            pass_sockets,
+            listen_address,
+            allocation,
+            command,
+        } => {
+            let settings = allocation_settings(&config, &allocation);
+            let lifetime = allocation_lifetime(&allocation);
+            let mappings: Vec<PortMapping> = ports
+                .iter()
+                .map(|name| {
+                    get_or_assign(&mut config, name, &settings, allocation.protocol, lifetime).0
+                })
+                .collect();
+
+            // Bind before letting go of the lock, so no other invocation sees the ports free
+            let sockets = if pass_sockets {
+                hold_sockets(&mappings, listen_address)
             } else {
-                println!("{:<20} {:<15} Description", "Name", "Port");
-                println!("{}", "-".repeat(60));
-                for mapping in ports {
-                    println!(
-                        "{:<20} {:<15} {}",
-                        mapping.name,
-                        mapping.port.display(),
-                        mapping.description.as_deref().unwrap_or("-")
-                    );
+                Vec::new()
+            };
+
+            // The command may run for a long time; don't block other invocations
+            drop(lock);
+            run_with_ports(&mappings, set_port, &sockets, &command);
+        }
+        Commands::Compose {
+            file,
+            output,
+            stdout,
+            force,
+            allocation,
+        } => {
+            let file = match file.or_else(|| {
+                std::env::current_dir()
+                    .ok()
+                    .and_then(|cwd| compose::find_file(&cwd))
+            }) {
+                Some(file) => file,
+                None => {
+                    eprintln!("No compose file found in the current directory; pass --file");
+                    std::process::exit(error::EXIT_NOT_FOUND);
                 }
+            };
+            let parsed = match compose::load(&file) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    fail("Error reading compose file", e);
+                }
+            };
+            for skipped in &parsed.skipped {
+                eprintln!("Keeping unrecognized port entry {}", skipped);
+            }
+            // Outside a project, namespace the ports by the compose project
+            if config.scope.is_none() {
+                config.scope = Some(parsed.project.clone());
+            }
+
+            let settings = allocation_settings(&config, &allocation);
+            let lifetime = allocation_lifetime(&allocation);
+            let assigned: Vec<_> = parsed
+                .ports
+                .iter()
+                .map(|port| {
+                    let name = port.name();
+                    let (mapping, _) = match port.count() {
+                        1 => get_or_assign(&mut config, &name, &settings, port.protocol, lifetime),
+                        count => get_or_assign_block(
+                            &mut config,
+                            &name,
+                            count,
+                            &settings,
+                            port.protocol,
+                            lifetime,
+                        ),
+                    };
+                    (port.clone(), mapping.port)
+                })
+                .collect();
+
+            let output = output.unwrap_or_else(|| compose::override_path(&file));
+            let rendered = match compose::override_content(&output, &assigned, &parsed.skipped) {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    fail("Error rendering override file", e);
+                }
+            };
+            if stdout {
+                print!("{}", rendered.content);
+            } else if !rendered.changed {
+                eprintln!("{} is up to date", output.display());
+            } else if rendered.hand_edited && !force {
+                eprintln!(
+                    "{} has comments or formatting that rewriting it would drop; \
+                     use --stdout to see the result, or --force to write it anyway",
+                    output.display()
+                );
+                std::process::exit(error::EXIT_INVALID);
+            } else {
+                if let Err(e) = compose::write_override(&output, &rendered.content) {
+                    fail("Error writing override file", e);
+                }
+                eprintln!("Wrote {} ({} port(s))", output.display(), assigned.len());
             }
         }
         Commands::ResetDefaults => {
             if let Err(e) = DefaultsConfig::reset() {
-                eprintln!("Error resetting defaults: {}", e);
-                std::process::exit(1);
+                fail("Error resetting defaults", e);
             }
             eprintln!("Default ports reset successfully");
-            eprintln!("File location: ~/.config/ports-manager/defaults.toml");
+            eprintln!("File location: {}", defaults_location());
             eprintln!("You can edit this file to customize default port mappings");
         }
-        Commands::SyncDefaults => match DefaultsConfig::sync() {
-            Ok(diff) => {
-                eprintln!("Defaults synchronized successfully");
-                if diff > 0 {
-                    eprintln!("  - {} new default(s) added", diff);
-                } else if diff < 0 {
-                    eprintln!("  - {} default(s) removed", -diff);
+        Commands::SyncDefaults { dry_run } => match DefaultsConfig::sync(dry_run) {
+            Ok(changes) if dry_run => {
+                for change in &changes {
+                    println!("{}", change);
+                }
+                if changes.is_empty() {
+                    eprintln!("Defaults are up to date");
                 } else {
+                    eprintln!("Dry run; {} not changed", defaults_location());
+                }
+            }
+            Ok(changes) => {
+                eprintln!("Defaults synchronized successfully");
+                for change in &changes {
+                    eprintln!("  {}", change);
+                }
+                if changes.is_empty() {
                     eprintln!("  - No changes (already up to date)");
                 }
-                eprintln!("\nFile location: ~/.config/ports-manager/defaults.toml");
+                eprintln!("\nFile location: {}", defaults_location());
                 eprintln!("\nTip: Add defaults to 'ignored_defaults' array in config.toml to exclude them");
             }
             Err(e) => {
-                eprintln!("Error syncing defaults: {}", e);
-                std::process::exit(1);
+                fail("Error syncing defaults", e);
+            }
+        },
+        // Handled before the config is loaded
+        Commands::RestoreBackup | Commands::Doctor { .. } | Commands::Daemon { .. } => {}
+    }
+}
+
+/// The namespace from `--global`, `--project` and the working directory, and
+/// the discovered project whose `.ports.toml` applies to it.
+fn namespace(cli: &Cli) -> (Option<String>, Option<project::Project>) {
+    if cli.global {
+        return (None, None);
+    }
+    let discovered = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| project::discover(&cwd));
+    match (&cli.project, discovered) {
+        // An explicit --project only uses the project file if it names this project
+        (Some(name), Some(found)) if *name != found.name => (Some(name.clone()), None),
+        (Some(name), found) => (Some(name.clone()), found),
+        (None, Some(found)) => (Some(found.name.clone()), Some(found)),
+        (None, None) => (None, None),
+    }
+}
+
+/// Runs the daemon in the foreground, or stops the running one.
+fn run_daemon(stop: bool, lock_timeout: Duration) {
+    let socket = match daemon::socket_path() {
+        Ok(socket) => socket,
+        Err(e) => {
+            fail("Error locating daemon socket", e);
+        }
+    };
+    if stop {
+        match daemon::Client::connect(&socket) {
+            Ok(Some(mut client)) => {
+                if let Err(e) = client.shutdown() {
+                    fail("Error stopping daemon", e);
+                }
+                eprintln!("Daemon stopped");
+            }
+            Ok(None) => {
+                eprintln!("No daemon is running on {}", socket.display());
+                std::process::exit(error::EXIT_NOT_FOUND);
+            }
+            Err(e) => {
+                fail("Error connecting to daemon", e);
+            }
+        }
+        return;
+    }
+    eprintln!("Starting daemon on {}", socket.display());
+    if let Err(e) = daemon::serve(&socket, lock_timeout) {
+        fail("Error running daemon", e);
+    }
+}
+
+/// The running daemon, if any. Falls back to the files if it can't be reached.
+fn connect_daemon() -> Option<daemon::Client> {
+    let socket = daemon::socket_path().ok()?;
+    daemon::Client::connect(&socket).ok().flatten()
+}
+
+/// Runs `get`, `remove`, `exec` or `list` through the daemon.
+fn with_daemon(mut client: daemon::Client, command: Commands, namespace: &Namespace) {
+    match command {
+        Commands::Get {
+            name,
+            format,
+            block,
+            allocation,
+        } => {
+            let (mapping, source) =
+                daemon_allocate(&mut client, &name, namespace, &allocation, block);
+            print_allocation(&mapping, source, format, block.is_some());
+        }
+        Commands::Remove { name } => match client.release(&name, namespace) {
+            Ok((removed, pool)) => report_removal(&removed, pool.as_deref()),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(e.exit_code());
             }
         },
+        Commands::Exec {
+            ports,
+            set_port,
+            pass_sockets,
+            listen_address,
+            allocation,
+            command,
+        } => {
+            let mappings: Vec<PortMapping> = ports
+                .iter()
+                .map(|name| daemon_allocate(&mut client, name, namespace, &allocation, None).0)
+                .collect();
+            let sockets = if pass_sockets {
+                hold_sockets(&mappings, listen_address)
+            } else {
+                Vec::new()
+            };
+            run_with_ports(&mappings, set_port, &sockets, &command);
+        }
+        Commands::List {
+            format,
+            all,
+            all_projects,
+        } => {
+            let entries = match client.list(namespace, all, all_projects) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(e.exit_code());
+                }
+            };
+            let records: Vec<PortRecord> = entries
+                .iter()
+                .map(|entry| PortRecord::new(&entry.mapping, entry.source))
+                .collect();
+            let leased: Vec<usize> = entries
+                .iter()
+                .map(|entry| entry.leases.unwrap_or(0))
+                .collect();
+            print_list(&records, &leased, format);
+        }
+        _ => unreachable!("only called for commands that use the daemon"),
+    }
+}
+
+/// Prints `list` output; `leased[i]` is the number of ports leased from
+/// `records[i]` if it is a range.
+fn print_list(records: &[PortRecord], leased: &[usize], format: OutputFormat) {
+    if format != OutputFormat::Text {
+        print_rendered(output::render_list(records, format));
+        return;
+    }
+    if records.is_empty() {
+        println!("No port mappings configured");
+        return;
+    }
+    println!("{:<20} {:<22} Description", "Name", "Port");
+    println!("{}", "-".repeat(67));
+    for (record, &leased) in records.iter().zip(leased) {
+        let port = match (record.port, record.start, record.end) {
+            (Some(port), _, _) => port.to_string(),
+            // Show pool usage for ranges that have leases
+            (None, Some(start), Some(end)) if leased > 0 => format!(
+                "{}-{} [{}/{}]",
+                start,
+                end,
+                leased,
+                u32::from(end) - u32::from(start) + 1
+            ),
+            (None, Some(start), Some(end)) => format!("{}-{}", start, end),
+            _ => "-".to_string(),
+        };
+        println!(
+            "{:<20} {:<22} {}",
+            qualified(record.project.as_deref(), &record.name),
+            port,
+            record.description.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+/// Like [`get_or_assign`], through the daemon.
+fn daemon_allocate(
+    client: &mut daemon::Client,
+    name: &str,
+    namespace: &Namespace,
+    args: &AllocationArgs,
+    block: Option<u16>,
+) -> (PortMapping, MappingSource) {
+    let request = Request::Allocate {
+        name: name.to_string(),
+        namespace: namespace.clone(),
+        overrides: allocation_overrides(args),
+        protocol: args.protocol,
+        block,
+        ttl: allocation_ttl(args),
+        bind_pid: args.bind_pid,
+    };
+    let response = match client.request(&request) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+    match (response.entry, response.allocated) {
+        (Some(entry), Some(how)) => {
+            let what = if block.is_some() { "block" } else { "port" };
+            report_allocation(entry.mapping, how, what)
+        }
+        _ => {
+            eprintln!("Error talking to daemon: incomplete response");
+            std::process::exit(error::EXIT_FAILURE);
+        }
+    }
+}
+
+/// Binds every port of `mappings` on `address` for `exec --pass-sockets`;
+/// exits if one is taken.
+fn hold_sockets(mappings: &[PortMapping], address: IpAddr) -> Vec<(String, HeldSocket)> {
+    if !cfg!(unix) {
+        eprintln!("--pass-sockets needs Unix file descriptor passing");
+        std::process::exit(error::EXIT_INVALID);
+    }
+    let mut sockets = Vec::new();
+    for mapping in mappings {
+        let (start, end) = mapping.port.bounds();
+        for port in start..=end {
+            match HeldSocket::bind(address, port, mapping.protocol) {
+                Ok(held) => sockets.extend(held.into_iter().map(|s| (mapping.name.clone(), s))),
+                Err(e) => {
+                    eprintln!(
+                        "Error binding {} for '{}': {}",
+                        SocketAddr::new(address, port),
+                        mapping.qualified_name(),
+                        e
+                    );
+                    std::process::exit(error::EXIT_FAILURE);
+                }
+            }
+        }
+    }
+    sockets
+}
+
+/// Runs `command` with NAME_PORT set for each mapping (and PORT for the
+/// first), passing it `sockets`.
+fn run_with_ports(
+    mappings: &[PortMapping],
+    set_port: bool,
+    sockets: &[(String, HeldSocket)],
+    command: &[OsString],
+) -> ! {
+    let vars = match export::collect_env_vars(mappings, "", export::DEFAULT_SUFFIX) {
+        Ok(vars) => vars,
+        Err(e) => {
+            fail("Error setting up the environment", e);
+        }
+    };
+    let mut env = Vec::new();
+    if set_port {
+        if let Some(PortSpec::Single(port)) = mappings.first().map(|m| &m.port) {
+            env.push(("PORT".to_string(), port.to_string()));
+        }
+    }
+    env.extend(vars.into_iter().map(|(key, port)| (key, port.to_string())));
+    exec::run(&command[0], &command[1..], &env, sockets)
+}
+
+/// Prints what `get` resolved; text output of a block lists every port.
+fn print_allocation(
+    mapping: &PortMapping,
+    source: MappingSource,
+    format: OutputFormat,
+    block: bool,
+) {
+    if block && format == OutputFormat::Text {
+        let ports: Vec<String> = match mapping.port {
+            PortSpec::Single(port) => vec![port.to_string()],
+            PortSpec::Range { start, end } => (start..=end).map(|port| port.to_string()).collect(),
+        };
+        println!("{}", ports.join(" "));
+    } else {
+        print_mapping(mapping, source, format);
+    }
+}
+
+fn report_removal(removed: &PortMapping, pool: Option<&str>) {
+    match pool {
+        Some(pool) => eprintln!(
+            "Released {} back to pool '{}'",
+            removed.qualified_name(),
+            pool
+        ),
+        None => eprintln!("Removed port mapping: {}", removed.qualified_name()),
+    }
+}
+
+fn print_mapping(mapping: &PortMapping, source: MappingSource, format: OutputFormat) {
+    if format == OutputFormat::Text {
+        println!("{}", mapping.port.display());
+    } else {
+        print_rendered(output::render_one(
+            &PortRecord::new(mapping, source),
+            format,
+        ));
+    }
+}
+
+fn print_rendered(rendered: ports_manager::Result<String>) {
+    match rendered {
+        Ok(text) => print!("{}", text),
+        Err(e) => {
+            fail("Error formatting output", e);
+        }
+    }
+}
+
+/// Prints `err` after `context` and exits with the error's exit code.
+fn fail(context: &str, err: Error) -> ! {
+    eprintln!("{}: {}", context, err);
+    std::process::exit(err.exit_code());
+}
+
+/// Where defaults.toml lives, for messages.
+fn defaults_location() -> String {
+    DefaultsConfig::defaults_path()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| "defaults.toml".to_string())
+}
+
+/// What `set` changed about a mapping, e.g. `port 8080 -> 8081`.
+fn describe_changes(before: &PortMapping, after: &PortMapping) -> Vec<String> {
+    let mut changes = Vec::new();
+    if before.port != after.port {
+        changes.push(format!(
+            "port {} -> {}",
+            before.port.display(),
+            after.port.display()
+        ));
+    }
+    if before.description != after.description {
+        changes.push(format!(
+            "description {:?} -> {:?}",
+            before.description.as_deref().unwrap_or(""),
+            after.description.as_deref().unwrap_or("")
+        ));
+    }
+    if before.protocol != after.protocol {
+        changes.push(format!(
+            "protocol {} -> {}",
+            before.protocol.as_str(),
+            after.protocol.as_str()
+        ));
+    }
+    if before.shared != after.shared {
+        changes.push(
+            if after.shared {
+                "now shared"
+            } else {
+                "no longer shared"
+            }
+            .to_string(),
+        );
+    }
+    if before.is_auto() && !after.is_auto() {
+        changes.push("now pinned".to_string());
+    }
+    changes
+}
+
+/// Name as shown to the user, e.g. `myrepo/api` inside a project.
+fn qualified(project: Option<&str>, name: &str) -> String {
+    match project {
+        Some(project) => format!("{}/{}", project, name),
+        None => name.to_string(),
+    }
+}
+
+/// The allocation settings with this call's overrides.
+fn allocation_settings(config: &Config, args: &AllocationArgs) -> AllocationConfig {
+    allocation_overrides(args).apply(&config.allocation)
+}
+
+/// This call's allocation overrides; exits on invalid options.
+fn allocation_overrides(args: &AllocationArgs) -> AllocationOverrides {
+    match args.overrides() {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("Error parsing allocation options: {}", e);
+            std::process::exit(error::EXIT_INVALID);
+        }
     }
 }
+
+/// How long an auto-assigned mapping lives, from `--ttl` and `--bind-pid`.
+#[derive(Clone, Copy)]
+struct Lifetime {
+    ttl: Option<u64>,
+    owner: Option<Owner>,
+}
+
+/// The `--ttl` and `--bind-pid` options; exits if either is invalid.
+fn allocation_lifetime(args: &AllocationArgs) -> Lifetime {
+    let owner = args.bind_pid.map(|pid| match Owner::running(pid) {
+        Ok(owner) => owner,
+        Err(e) => {
+            fail("Error parsing --bind-pid", e);
+        }
+    });
+    Lifetime {
+        ttl: allocation_ttl(args),
+        owner,
+    }
+}
+
+/// The `--ttl` option in seconds; exits if it is invalid.
+fn allocation_ttl(args: &AllocationArgs) -> Option<u64> {
+    let ttl = args.ttl.as_deref()?;
+    match expiry::parse_duration(ttl) {
+        Ok(secs) => Some(secs),
+        Err(e) => {
+            eprintln!("Error parsing --ttl: {}", e);
+            std::process::exit(error::EXIT_INVALID);
+        }
+    }
+}
+
+/// The `--lock-timeout` option; exits if it is not a usable number of seconds.
+fn lock_timeout(cli: &Cli) -> Duration {
+    // Negative timeouts mean "don't wait"; NaN must still be rejected
+    let secs = if cli.lock_timeout < 0.0 {
+        0.0
+    } else {
+        cli.lock_timeout
+    };
+    match Duration::try_from_secs_f64(secs) {
+        Ok(timeout) => timeout,
+        Err(_) => {
+            eprintln!(
+                "Error parsing --lock-timeout: {} is not a valid number of seconds",
+                cli.lock_timeout
+            );
+            std::process::exit(error::EXIT_INVALID);
+        }
+    }
+}
+
+/// Resolves `name`, auto-assigning and saving a free port if it isn't configured.
+fn get_or_assign(
+    config: &mut Config,
+    name: &str,
+    settings: &AllocationConfig,
+    protocol: Protocol,
+    lifetime: Lifetime,
+) -> (PortMapping, MappingSource) {
+    let allocated = config.allocate(name, settings, protocol);
+    save_allocation(config, name, allocated, "port", lifetime)
+}
+
+/// Resolves `name` as a block of `count` consecutive ports, allocating one if needed.
+fn get_or_assign_block(
+    config: &mut Config,
+    name: &str,
+    count: u16,
+    settings: &AllocationConfig,
+    protocol: Protocol,
+    lifetime: Lifetime,
+) -> (PortMapping, MappingSource) {
+    let allocated = config.allocate_block(name, count, settings, protocol);
+    save_allocation(config, name, allocated, "block", lifetime)
+}
+
+/// Saves and reports a newly allocated mapping, and records the use of an
+/// existing auto-assigned one; exits on errors.
+fn save_allocation(
+    config: &mut Config,
+    name: &str,
+    allocated: ports_manager::Result<(PortMapping, Allocated)>,
+    what: &str,
+    lifetime: Lifetime,
+) -> (PortMapping, MappingSource) {
+    let (mapping, how) = match allocated {
+        Ok(allocated) => allocated,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+    let touched = config.touch(name, lifetime.ttl, expiry::now());
+    let bound = match lifetime.owner.map(|owner| config.bind(name, owner)) {
+        Some(Ok(bound)) => bound,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+        None => false,
+    };
+    if touched || bound || !matches!(how, Allocated::Existing(_)) {
+        if let Err(e) = config.save() {
+            fail("Error saving config", e);
+        }
+    }
+    report_allocation(mapping, how, what)
+}
+
+/// Reports a newly allocated mapping and returns it with where it came from.
+fn report_allocation(
+    mapping: PortMapping,
+    how: Allocated,
+    what: &str,
+) -> (PortMapping, MappingSource) {
+    let verb = match how {
+        Allocated::Existing(source) => return (mapping, source),
+        Allocated::Leased => "Leased",
+        Allocated::Assigned => "Auto-assigned",
+    };
+    eprintln!(
+        "{} {} for '{}': {}",
+        verb,
+        what,
+        mapping.qualified_name(),
+        mapping.port.display()
+    );
+    (mapping, MappingSource::User)
+}
@@ -0,0 +1,258 @@
+use crate::config::{MappingSource, PortMapping, PortSpec};
+use crate::error::{Error, Result};
+use crate::port_finder::Protocol;
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// Output format for `list` and `get`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable table for `list`, bare port for `get`
+    #[default]
+    Text,
+    Json,
+    Toml,
+    Yaml,
+    Csv,
+    Tsv,
+}
+
+/// Flat, stable representation of a mapping for machine-readable output.
+///
+/// Every field is always present in JSON and YAML output; `port` is set for
+/// single ports, `start`/`end` for ranges, and the others are `null`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PortRecord {
+    pub name: String,
+    pub port: Option<u16>,
+    pub start: Option<u16>,
+    pub end: Option<u16>,
+    pub description: Option<String>,
+    pub source: MappingSource,
+    pub project: Option<String>,
+    pub protocol: Protocol,
+}
+
+impl PortRecord {
+    pub fn new(mapping: &PortMapping, source: MappingSource) -> Self {
+        let (port, start, end) = match mapping.port {
+            PortSpec::Single(port) => (Some(port), None, None),
+            PortSpec::Range { start, end } => (None, Some(start), Some(end)),
+        };
+        PortRecord {
+            name: mapping.name.clone(),
+            port,
+            start,
+            end,
+            description: mapping.description.clone(),
+            source,
+            project: mapping.project.clone(),
+            protocol: mapping.protocol,
+        }
+    }
+}
+
+/// TOML has no top-level arrays, so lists are wrapped in a `[[ports]]` table.
+#[derive(Serialize)]
+struct PortRecords<'a> {
+    ports: &'a [PortRecord],
+}
+
+const COLUMNS: [&str; 8] = [
+    "name",
+    "port",
+    "start",
+    "end",
+    "description",
+    "source",
+    "project",
+    "protocol",
+];
+
+/// Renders a list of records. `Text` is handled by the caller.
+pub fn render_list(records: &[PortRecord], format: OutputFormat) -> Result<String> {
+    match format {
+        OutputFormat::Text => Err(Error::Validation(
+            "text output is rendered by the caller".to_string(),
+        )),
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(records)? + "\n"),
+        OutputFormat::Toml => Ok(toml::to_string_pretty(&PortRecords { ports: records })?),
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(records)?),
+        OutputFormat::Csv => Ok(render_delimited(records, ',', csv_field)),
+        OutputFormat::Tsv => Ok(render_delimited(records, '\t', tsv_field)),
+    }
+}
+
+/// Renders a single record, as printed by `get`.
+pub fn render_one(record: &PortRecord, format: OutputFormat) -> Result<String> {
+    match format {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(record)? + "\n"),
+        OutputFormat::Toml => Ok(toml::to_string_pretty(record)?),
+        OutputFormat::Yaml => Ok(serde_yaml::to_string(record)?),
+        _ => render_list(std::slice::from_ref(record), format),
+    }
+}
+
+fn render_delimited(records: &[PortRecord], delimiter: char, field: fn(&str) -> String) -> String {
+    let separator = delimiter.to_string();
+    let mut out = COLUMNS.join(&separator);
+    out.push('\n');
+
+    for record in records {
+        let source = match record.source {
+            MappingSource::User => "user",
+            MappingSource::Project => "project",
+            MappingSource::System => "system",
+            MappingSource::Default => "default",
+        };
+        let row = [
+            field(&record.name),
+            optional(record.port),
+            optional(record.start),
+            optional(record.end),
+            field(record.description.as_deref().unwrap_or("")),
+            source.to_string(),
+            field(record.project.as_deref().unwrap_or("")),
+            record.protocol.as_str().to_string(),
+        ];
+        out.push_str(&row.join(&separator));
+        out.push('\n');
+    }
+    out
+}
+
+fn optional(value: Option<u16>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// RFC 4180 quoting: wrap in quotes when needed and double embedded quotes.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// TSV has no quoting, so tabs and line breaks are replaced with spaces.
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<PortRecord> {
+        vec![
+            PortRecord::new(
+                &PortMapping::new("web", PortSpec::Single(8080), Some("Web, \"main\"")),
+                MappingSource::User,
+            ),
+            PortRecord::new(
+                &PortMapping::new(
+                    "pool",
+                    PortSpec::Range {
+                        start: 9000,
+                        end: 9010,
+                    },
+                    None,
+                ),
+                MappingSource::Default,
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_render_json_is_stable() {
+        let out = render_list(&records(), OutputFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!([
+                {
+                    "name": "web",
+                    "port": 8080,
+                    "start": null,
+                    "end": null,
+                    "description": "Web, \"main\"",
+                    "source": "user",
+                    "project": null,
+                    "protocol": "tcp"
+                },
+                {
+                    "name": "pool",
+                    "port": null,
+                    "start": 9000,
+                    "end": 9010,
+                    "description": null,
+                    "source": "default",
+                    "project": null,
+                    "protocol": "tcp"
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_render_one_json_is_object() {
+        let out = render_one(&records()[0], OutputFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(value["port"], 8080);
+        assert_eq!(value["source"], "user");
+    }
+
+    #[test]
+    fn test_render_toml_wraps_list() {
+        let out = render_list(&records(), OutputFormat::Toml).unwrap();
+        let value: toml::Table = out.parse().unwrap();
+        let ports = value["ports"].as_array().unwrap();
+        assert_eq!(ports.len(), 2);
+        assert_eq!(ports[1]["start"].as_integer(), Some(9000));
+    }
+
+    #[test]
+    fn test_render_includes_project() {
+        let mut mapping = PortMapping::new("api", PortSpec::Single(8080), None);
+        mapping.project = Some("shop".to_string());
+        let record = PortRecord::new(&mapping, MappingSource::User);
+
+        let out = render_one(&record, OutputFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(value["name"], "api");
+        assert_eq!(value["project"], "shop");
+
+        let out = render_one(&record, OutputFormat::Csv).unwrap();
+        assert_eq!(out.lines().nth(1), Some("api,8080,,,,user,shop,tcp"));
+    }
+
+    #[test]
+    fn test_render_yaml() {
+        let out = render_list(&records(), OutputFormat::Yaml).unwrap();
+        assert!(out.contains("name: web"));
+        assert!(out.contains("source: default"));
+    }
+
+    #[test]
+    fn test_render_csv_quotes_fields() {
+        let out = render_list(&records(), OutputFormat::Csv).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "name,port,start,end,description,source,project,protocol"
+        );
+        assert_eq!(lines[1], "web,8080,,,\"Web, \"\"main\"\"\",user,,tcp");
+        assert_eq!(lines[2], "pool,,9000,9010,,default,,tcp");
+    }
+
+    #[test]
+    fn test_render_tsv() {
+        let out = render_list(&records(), OutputFormat::Tsv).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "name\tport\tstart\tend\tdescription\tsource\tproject\tprotocol"
+        );
+        assert_eq!(lines[2], "pool\t\t9000\t9010\t\tdefault\t\ttcp");
+    }
+}
@@ -1,5 +1,50 @@
-use std::net::TcpListener;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
 
+/// Transport protocol a port must be free for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+    Both,
+}
+
+impl Protocol {
+    pub fn is_tcp(&self) -> bool {
+        *self == Protocol::Tcp
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Both => "both",
+        }
+    }
+}
+
+/// Where in the range the search for a free port starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Lowest free port in the range
+    #[default]
+    Lowest,
+    /// Random free port in the range
+    Random,
+    /// Start at a position derived from the name, so a service tends to get
+    /// the same port on every machine
+    Hash,
+}
+
+/// Lowest free port in 8000-65535 that isn't in `exclude_ports`.
 pub fn find_available_port(exclude_ports: &[u16]) -> Option<u16> {
     find_available_port_in_range(8000, 65535, exclude_ports)
 }
@@ -8,8 +53,170 @@ pub fn find_available_port_in_range(start: u16, end: u16, exclude_ports: &[u16])
     (start..=end).find(|&port| !exclude_ports.contains(&port) && is_port_available(port))
 }
 
+/// Finds a free, non-excluded port in `start..=end` according to `policy`.
+///
+/// The search starts at an offset chosen by the policy and wraps around, so it
+/// only fails when every port in the range is excluded or taken.
+pub fn find_available_port_with_policy(
+    start: u16,
+    end: u16,
+    exclude_ports: &[u16],
+    policy: Policy,
+    name: &str,
+    protocol: Protocol,
+) -> Option<u16> {
+    if start > end {
+        return None;
+    }
+    let excluded: HashSet<u16> = exclude_ports.iter().copied().collect();
+    let size = u32::from(end) - u32::from(start) + 1;
+    let offset = match policy {
+        Policy::Lowest => 0,
+        Policy::Random => (RandomState::new().build_hasher().finish() % u64::from(size)) as u32,
+        Policy::Hash => (stable_hash(name) % u64::from(size)) as u32,
+    };
+
+    (0..size)
+        .map(|i| (u32::from(start) + (offset + i) % size) as u16)
+        .find(|port| !excluded.contains(port) && is_port_available_for(*port, protocol))
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is the same on every machine and Rust version.
+fn stable_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The OS range for ephemeral (outgoing) ports, which is best avoided for services.
+#[cfg(target_os = "linux")]
+pub fn ephemeral_port_range() -> Option<(u16, u16)> {
+    let content = std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").ok()?;
+    parse_port_range(&content)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn ephemeral_port_range() -> Option<(u16, u16)> {
+    None
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_port_range(content: &str) -> Option<(u16, u16)> {
+    let mut parts = content.split_whitespace();
+    let start = parts.next()?.parse().ok()?;
+    let end = parts.next()?.parse().ok()?;
+    Some((start, end))
+}
+
+/// Finds the first run of `count` consecutive free, non-excluded ports in
+/// `start..=end` and returns the first port of the run.
+pub fn find_available_block_in_range(
+    start: u16,
+    end: u16,
+    count: u16,
+    exclude_ports: &[u16],
+    protocol: Protocol,
+) -> Option<u16> {
+    if count == 0 {
+        return None;
+    }
+
+    let excluded: HashSet<u16> = exclude_ports.iter().copied().collect();
+    let mut run_start = start;
+    let mut run_len = 0;
+    for port in start..=end {
+        if excluded.contains(&port) || !is_port_available_for(port, protocol) {
+            run_len = 0;
+            continue;
+        }
+        if run_len == 0 {
+            run_start = port;
+        }
+        run_len += 1;
+        if run_len == count {
+            return Some(run_start);
+        }
+    }
+    None
+}
+
 fn is_port_available(port: u16) -> bool {
-    TcpListener::bind(format!("127.0.0.1:{}", port)).is_ok()
+    is_port_available_for(port, Protocol::Tcp)
+}
+
+/// Probes whether `port` can be bound for `protocol` on the IPv4 and IPv6
+/// wildcard and loopback addresses, so ports held by services listening on
+/// any interface are detected too.
+pub fn is_port_available_for(port: u16, protocol: Protocol) -> bool {
+    let addrs = [
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+    ];
+    addrs.iter().all(|addr| {
+        let tcp_free = || probe(TcpListener::bind(addr));
+        let udp_free = || probe(UdpSocket::bind(addr));
+        match protocol {
+            Protocol::Tcp => tcp_free(),
+            Protocol::Udp => udp_free(),
+            Protocol::Both => tcp_free() && udp_free(),
+        }
+    })
+}
+
+/// A socket bound to a port and kept open, so nothing else can take the port
+/// between allocating it and the service starting (see `exec --pass-sockets`).
+#[derive(Debug)]
+pub enum HeldSocket {
+    /// Listening TCP socket
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl HeldSocket {
+    /// Binds `port` on `address` for `protocol`: a listening TCP socket, a
+    /// UDP socket, or one of each for [`Protocol::Both`].
+    pub fn bind(address: IpAddr, port: u16, protocol: Protocol) -> io::Result<Vec<HeldSocket>> {
+        let addr = SocketAddr::new(address, port);
+        let mut sockets = Vec::new();
+        if protocol != Protocol::Udp {
+            sockets.push(HeldSocket::Tcp(TcpListener::bind(addr)?));
+        }
+        if protocol != Protocol::Tcp {
+            sockets.push(HeldSocket::Udp(UdpSocket::bind(addr)?));
+        }
+        Ok(sockets)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            HeldSocket::Tcp(listener) => listener.local_addr(),
+            HeldSocket::Udp(socket) => socket.local_addr(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for HeldSocket {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        match self {
+            HeldSocket::Tcp(listener) => listener.as_raw_fd(),
+            HeldSocket::Udp(socket) => socket.as_raw_fd(),
+        }
+    }
+}
+
+/// A failed bind only means "taken" if the address exists on this host; hosts
+/// without IPv6 report other errors, which don't count against the port.
+fn probe<T>(result: io::Result<T>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::AddrInUse | io::ErrorKind::PermissionDenied
+        ),
+    }
 }
 
 #[cfg(test)]
@@ -87,6 +294,46 @@ mod tests {
         assert!(is_port_available(port));
     }
 
+    #[test]
+    fn test_is_port_available_detects_wildcard_bind() {
+        let port = find_available_port_in_range(60000, 65535, &[]).unwrap();
+        let _listener = TcpListener::bind(("0.0.0.0", port)).unwrap();
+        assert!(!is_port_available_for(port, Protocol::Tcp));
+    }
+
+    #[test]
+    fn test_is_port_available_udp() {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        assert!(!is_port_available_for(port, Protocol::Udp));
+        assert!(!is_port_available_for(port, Protocol::Both));
+        drop(socket);
+        assert!(is_port_available_for(port, Protocol::Udp));
+    }
+
+    #[test]
+    fn test_is_port_available_ipv6_loopback() {
+        // Skip on hosts without IPv6
+        let Ok(listener) = TcpListener::bind(("::1", 0)) else {
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        assert!(!is_port_available_for(port, Protocol::Tcp));
+    }
+
+    #[test]
+    fn test_held_socket_keeps_port_taken() {
+        let port = find_available_port_in_range(44000, 44999, &[]).unwrap();
+        let held = HeldSocket::bind(Ipv4Addr::LOCALHOST.into(), port, Protocol::Tcp).unwrap();
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].local_addr().unwrap().port(), port);
+        assert!(!is_port_available_for(port, Protocol::Tcp));
+
+        drop(held);
+        assert!(is_port_available_for(port, Protocol::Tcp));
+    }
+
     #[test]
     fn test_multiple_sequential_ports() {
         let result1 = find_available_port(&[]);
@@ -134,6 +381,113 @@ mod tests {
         assert!(port >= 65530);
     }
 
+    #[test]
+    fn test_find_available_block() {
+        let start = find_available_block_in_range(8000, 65535, 5, &[], Protocol::Tcp).unwrap();
+        assert!(start >= 8000);
+        for port in start..start + 5 {
+            assert!(is_port_available(port));
+        }
+    }
+
+    #[test]
+    fn test_find_available_block_skips_short_runs() {
+        // 9200-9202 is a run of 3, too short for a block of 4 because 9203 is excluded
+        let exclude = vec![9203, 9205];
+        let start = find_available_block_in_range(9200, 9220, 4, &exclude, Protocol::Tcp)
+            .expect("no free block of 4 in 9206-9220");
+        assert!(start >= 9206);
+        for port in start..start + 4 {
+            assert!(!exclude.contains(&port));
+        }
+    }
+
+    #[test]
+    fn test_find_available_block_too_large_for_range() {
+        assert!(find_available_block_in_range(9300, 9302, 4, &[], Protocol::Tcp).is_none());
+        assert!(find_available_block_in_range(9300, 9302, 0, &[], Protocol::Tcp).is_none());
+    }
+
+    #[test]
+    fn test_find_available_block_around_bound_port() {
+        let start = find_available_block_in_range(60000, 65535, 3, &[], Protocol::Tcp).unwrap();
+        let _listener = TcpListener::bind(format!("127.0.0.1:{}", start + 1)).unwrap();
+
+        let block =
+            find_available_block_in_range(start, start + 20, 3, &[], Protocol::Tcp).unwrap();
+        assert!(block > start + 1);
+    }
+
+    #[test]
+    fn test_policy_lowest_matches_range_search() {
+        let lowest = find_available_port_with_policy(
+            9400,
+            9500,
+            &[9400],
+            Policy::Lowest,
+            "x",
+            Protocol::Tcp,
+        );
+        let plain = find_available_port_in_range(9400, 9500, &[9400]);
+        assert_eq!(lowest, plain);
+    }
+
+    #[test]
+    fn test_policy_hash_is_stable() {
+        let first =
+            find_available_port_with_policy(40000, 50000, &[], Policy::Hash, "api", Protocol::Tcp);
+        let second =
+            find_available_port_with_policy(40000, 50000, &[], Policy::Hash, "api", Protocol::Tcp);
+        assert!(first.is_some());
+        assert_eq!(first, second);
+        // Reference FNV-1a values, so the hash can never silently change
+        assert_eq!(stable_hash(""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash("a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_policy_random_stays_in_range() {
+        for _ in 0..10 {
+            let port = find_available_port_with_policy(
+                9600,
+                9700,
+                &[],
+                Policy::Random,
+                "x",
+                Protocol::Tcp,
+            )
+            .unwrap();
+            assert!((9600..=9700).contains(&port));
+        }
+    }
+
+    #[test]
+    fn test_policy_wraps_around_exclusions() {
+        let exclude: Vec<u16> = (9801..=9810).collect();
+        for policy in [Policy::Lowest, Policy::Random, Policy::Hash] {
+            if let Some(port) =
+                find_available_port_with_policy(9800, 9810, &exclude, policy, "svc", Protocol::Tcp)
+            {
+                assert_eq!(port, 9800);
+            }
+        }
+        assert!(find_available_port_with_policy(
+            9810,
+            9800,
+            &[],
+            Policy::Lowest,
+            "x",
+            Protocol::Tcp
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("32768\t60999\n"), Some((32768, 60999)));
+        assert_eq!(parse_port_range("garbage"), None);
+    }
+
     #[test]
     fn test_port_exclusion_order_independent() {
         let exclude1 = vec![8000, 8001, 8002];
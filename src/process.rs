@@ -0,0 +1,93 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Whether mappings can be bound to processes here; liveness is read from `/proc`.
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// The process an auto-assigned mapping is bound to with `get --bind-pid`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub pid: u32,
+    /// Start time in clock ticks since boot, which tells the process apart
+    /// from a later one that reuses its PID
+    pub start_time: u64,
+}
+
+impl Owner {
+    /// The running process `pid`, or `None` if there is no such process (or
+    /// [`SUPPORTED`] is false).
+    pub fn of(pid: u32) -> Option<Self> {
+        start_time(pid).map(|start_time| Owner { pid, start_time })
+    }
+
+    /// Like [`Owner::of`], with an error saying why `pid` can't be bound.
+    pub fn running(pid: u32) -> Result<Self> {
+        if !SUPPORTED {
+            return Err(Error::Validation(
+                "Binding ports to a process needs /proc and is only supported on Linux".to_string(),
+            ));
+        }
+        Self::of(pid)
+            .ok_or_else(|| Error::Validation(format!("No running process with PID {}", pid)))
+    }
+
+    /// Whether the process is still running, and isn't a later one with the same PID.
+    pub fn is_alive(&self) -> bool {
+        // Without /proc we can't tell, and must not release anything
+        !SUPPORTED || start_time(self.pid) == Some(self.start_time)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    linux::parse_start_time(&stat)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    /// Start time from the contents of `/proc/<pid>/stat`; `None` for a
+    /// process that has exited but not been reaped yet.
+    pub fn parse_start_time(stat: &str) -> Option<u64> {
+        // The command name in parentheses may itself contain spaces and parens
+        let after_name = &stat[stat.rfind(')')? + 1..];
+        let fields: Vec<&str> = after_name.split_whitespace().collect();
+        if matches!(fields.first(), Some(&"Z") | Some(&"X")) {
+            return None;
+        }
+        // Field 22 of the file; `fields` starts at field 3, the state
+        fields.get(19)?.parse().ok()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::process::Owner;
+
+        #[test]
+        fn test_parse_start_time() {
+            let stat = "4242 (my (odd) name) S 1 4242 4242 0 -1 4194560 100 0 0 0 \
+                        1 2 0 0 20 0 1 0 987654 1000000 100";
+            assert_eq!(parse_start_time(stat), Some(987654));
+            let zombie = stat.replace(") S ", ") Z ");
+            assert_eq!(parse_start_time(&zombie), None);
+            assert_eq!(parse_start_time("4242 (short) S 1"), None);
+        }
+
+        #[test]
+        fn test_owner_detects_pid_reuse() {
+            let owner = Owner::of(std::process::id()).expect("own process not found");
+            assert!(owner.is_alive());
+            let reused = Owner {
+                start_time: owner.start_time + 1,
+                ..owner
+            };
+            assert!(!reused.is_alive());
+        }
+    }
+}
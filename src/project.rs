@@ -0,0 +1,266 @@
+use crate::config::PortSpec;
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Marker file that makes a directory a project root, optionally naming the project.
+pub const MARKER_FILE: &str = ".ports-manager.toml";
+
+/// Checked-in file declaring the services a project needs. Also marks the project root.
+pub const PROJECT_FILE: &str = ".ports.toml";
+
+/// A project namespace discovered from the working directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Project {
+    pub name: String,
+    pub root: PathBuf,
+}
+
+impl Project {
+    /// The project's `.ports.toml`: the nearest one at or above the root, up to
+    /// the repository root, so a nested marker doesn't hide the repository's file.
+    pub fn file_path(&self) -> Option<PathBuf> {
+        for dir in self.root.ancestors() {
+            let path = dir.join(PROJECT_FILE);
+            if path.is_file() {
+                return Some(path);
+            }
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+        None
+    }
+
+    /// Loads the project's `.ports.toml` (see [`Project::file_path`]), if it has one.
+    pub fn load_file(&self) -> Result<Option<ProjectFile>> {
+        self.file_path()
+            .map(|path| ProjectFile::read(&path))
+            .transpose()
+    }
+}
+
+/// A service port declared in `.ports.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawProjectPort")]
+pub enum ProjectPort {
+    /// Same port for everyone, e.g. `web = 3000` or `pool = "8000-8010"`
+    Fixed(PortSpec),
+    /// `api = "auto"`: each user gets a free port recorded in their own config
+    Auto,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawProjectPort {
+    Spec(PortSpec),
+    Text(String),
+}
+
+impl TryFrom<RawProjectPort> for ProjectPort {
+    type Error = String;
+
+    fn try_from(raw: RawProjectPort) -> std::result::Result<Self, Self::Error> {
+        match raw {
+            RawProjectPort::Spec(spec) => Ok(ProjectPort::Fixed(spec)),
+            RawProjectPort::Text(text) if text.trim() == "auto" => Ok(ProjectPort::Auto),
+            RawProjectPort::Text(text) => PortSpec::parse(&text).map(ProjectPort::Fixed),
+        }
+    }
+}
+
+/// Contents of a project's `.ports.toml`:
+///
+/// ```toml
+/// project = "shop"   # optional, defaults to the directory name
+///
+/// [services]
+/// web = 3000
+/// api = "auto"
+/// workers = "8100-8110"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProjectFile {
+    #[serde(default)]
+    pub services: BTreeMap<String, ProjectPort>,
+}
+
+impl ProjectFile {
+    /// Reads and parses the `.ports.toml` at `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        toml::from_str(&content).map_err(|source| Error::parse(path, &content, source, None))
+    }
+}
+
+/// Walks up from `start` to the nearest directory containing a marker file,
+/// a `.ports.toml` or a `.git` entry. The project is named by the file's
+/// `project` key, falling back to the root directory's name.
+pub fn discover(start: &Path) -> Option<Project> {
+    for dir in start.ancestors() {
+        for file in [MARKER_FILE, PROJECT_FILE] {
+            let marker = dir.join(file);
+            if marker.is_file() {
+                let name = marker_name(&marker).or_else(|| dir_name(dir))?;
+                return Some(Project {
+                    name,
+                    root: dir.to_path_buf(),
+                });
+            }
+        }
+        if dir.join(".git").exists() {
+            return Some(Project {
+                name: dir_name(dir)?,
+                root: dir.to_path_buf(),
+            });
+        }
+    }
+    None
+}
+
+fn marker_name(marker: &Path) -> Option<String> {
+    let content = fs::read_to_string(marker).ok()?;
+    let table: toml::Table = content.parse().ok()?;
+    table
+        .get("project")?
+        .as_str()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+fn dir_name(dir: &Path) -> Option<String> {
+    dir.file_name().map(|n| n.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_tree(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ports-manager-project-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("repo").join("src").join("nested")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_discover_git_root() {
+        let tree = temp_tree("git");
+        let repo = tree.join("repo");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+
+        let project = discover(&repo.join("src").join("nested")).unwrap();
+        assert_eq!(project.name, "repo");
+        assert_eq!(project.root, repo);
+        let _ = fs::remove_dir_all(&tree);
+    }
+
+    #[test]
+    fn test_discover_marker_with_name() {
+        let tree = temp_tree("marker");
+        let repo = tree.join("repo");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::write(
+            repo.join("src").join(MARKER_FILE),
+            "project = \"shop-api\"\n",
+        )
+        .unwrap();
+
+        // The nearest root wins, so the marker in src/ shadows the git root
+        let project = discover(&repo.join("src").join("nested")).unwrap();
+        assert_eq!(project.name, "shop-api");
+        assert_eq!(project.root, repo.join("src"));
+        let _ = fs::remove_dir_all(&tree);
+    }
+
+    #[test]
+    fn test_discover_project_file() {
+        let tree = temp_tree("project-file");
+        let repo = tree.join("repo");
+        fs::write(
+            repo.join(PROJECT_FILE),
+            "project = \"shop\"\n[services]\nweb = 3000\napi = \"auto\"\npool = \"8100-8110\"\n",
+        )
+        .unwrap();
+
+        let project = discover(&repo.join("src").join("nested")).unwrap();
+        assert_eq!(project.name, "shop");
+
+        let file = project.load_file().unwrap().unwrap();
+        assert!(matches!(
+            file.services["web"],
+            ProjectPort::Fixed(PortSpec::Single(3000))
+        ));
+        assert!(matches!(file.services["api"], ProjectPort::Auto));
+        assert!(matches!(
+            file.services["pool"],
+            ProjectPort::Fixed(PortSpec::Range {
+                start: 8100,
+                end: 8110
+            })
+        ));
+        let _ = fs::remove_dir_all(&tree);
+    }
+
+    #[test]
+    fn test_load_file_above_nested_marker() {
+        let tree = temp_tree("nested-marker");
+        let repo = tree.join("repo");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::write(repo.join(PROJECT_FILE), "[services]\nweb = 3000\n").unwrap();
+        fs::write(repo.join("src").join(MARKER_FILE), "project = \"api\"\n").unwrap();
+        // Outside the repository, so never consulted
+        fs::write(tree.join(PROJECT_FILE), "[services]\nother = 4000\n").unwrap();
+
+        let project = discover(&repo.join("src").join("nested")).unwrap();
+        assert_eq!(project.root, repo.join("src"));
+        assert_eq!(project.file_path(), Some(repo.join(PROJECT_FILE)));
+        let file = project.load_file().unwrap().unwrap();
+        assert!(file.services.contains_key("web"));
+
+        fs::remove_file(repo.join(PROJECT_FILE)).unwrap();
+        assert!(project.load_file().unwrap().is_none());
+        let _ = fs::remove_dir_all(&tree);
+    }
+
+    #[test]
+    fn test_load_file_rejects_invalid_port() {
+        let tree = temp_tree("invalid-file");
+        let repo = tree.join("repo");
+        fs::write(repo.join(PROJECT_FILE), "[services]\nweb = \"soon\"\n").unwrap();
+
+        let project = discover(&repo).unwrap();
+        let err = project.load_file().unwrap_err().to_string();
+        assert!(err.contains(PROJECT_FILE));
+        let _ = fs::remove_dir_all(&tree);
+    }
+
+    #[test]
+    fn test_load_file_missing() {
+        let tree = temp_tree("no-file");
+        let repo = tree.join("repo");
+        fs::write(repo.join(MARKER_FILE), "").unwrap();
+
+        let project = discover(&repo).unwrap();
+        assert!(project.load_file().unwrap().is_none());
+        let _ = fs::remove_dir_all(&tree);
+    }
+
+    #[test]
+    fn test_discover_empty_marker_uses_dir_name() {
+        let tree = temp_tree("empty-marker");
+        let repo = tree.join("repo");
+        fs::write(repo.join(MARKER_FILE), "").unwrap();
+
+        let project = discover(&repo.join("src")).unwrap();
+        assert_eq!(project.name, "repo");
+        let _ = fs::remove_dir_all(&tree);
+    }
+}
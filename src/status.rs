@@ -0,0 +1,340 @@
+use crate::config::{MappingSource, PortMapping, PortSpec};
+use crate::listeners::{Listener, ProcessInfo, SocketProtocol};
+use crate::port_finder::{self, Protocol};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::IsTerminal;
+
+/// Output format for `status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum StatusFormat {
+    /// Colored table
+    #[default]
+    Text,
+    Json,
+}
+
+/// When to color `status` output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal and NO_COLOR is unset
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+/// What is happening on a mapping's port(s) right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortState {
+    /// Nothing is listening
+    Free,
+    /// Held by a process whose name matches the mapping
+    Expected,
+    /// Held by an unrelated process
+    Other,
+    /// Held by a process we can't see (another user's, or no /proc)
+    InUse,
+}
+
+impl PortState {
+    fn label(self) -> &'static str {
+        match self {
+            PortState::Free => "free",
+            PortState::Expected => "expected",
+            PortState::Other => "other",
+            PortState::InUse => "in use",
+        }
+    }
+
+    /// ANSI SGR color code
+    fn color(self) -> &'static str {
+        match self {
+            PortState::Free => "2",
+            PortState::Expected => "32",
+            PortState::Other => "31",
+            PortState::InUse => "33",
+        }
+    }
+}
+
+/// Status of one mapping, as printed by `status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PortStatus {
+    pub name: String,
+    pub project: Option<String>,
+    pub port: Option<u16>,
+    pub start: Option<u16>,
+    pub end: Option<u16>,
+    pub source: MappingSource,
+    pub state: PortState,
+    pub listeners: Vec<Listener>,
+}
+
+/// Classifies `mapping` against the live `listeners`. Without listener
+/// information (non-Linux), ports are probed by binding instead.
+pub fn check(
+    mapping: &PortMapping,
+    source: MappingSource,
+    listeners: Option<&[Listener]>,
+) -> PortStatus {
+    let (port, start, end) = match mapping.port {
+        PortSpec::Single(port) => (Some(port), port, port),
+        PortSpec::Range { start, end } => (None, start, end),
+    };
+
+    let (state, matching) = match listeners {
+        Some(listeners) => {
+            let matching: Vec<Listener> = listeners
+                .iter()
+                .filter(|l| (start..=end).contains(&l.port()))
+                .filter(|l| protocol_matches(mapping.protocol, l.protocol))
+                .cloned()
+                .collect();
+            (classify(&mapping.name, &matching), matching)
+        }
+        None => {
+            let busy = (start..=end)
+                .any(|port| !port_finder::is_port_available_for(port, mapping.protocol));
+            let state = if busy {
+                PortState::InUse
+            } else {
+                PortState::Free
+            };
+            (state, Vec::new())
+        }
+    };
+
+    PortStatus {
+        name: mapping.name.clone(),
+        project: mapping.project.clone(),
+        port,
+        start: port.is_none().then_some(start),
+        end: port.is_none().then_some(end),
+        source,
+        state,
+        listeners: matching,
+    }
+}
+
+/// A TCP listener doesn't block a UDP service and vice versa.
+fn protocol_matches(protocol: Protocol, socket: SocketProtocol) -> bool {
+    !matches!(
+        (protocol, socket),
+        (Protocol::Tcp, SocketProtocol::Udp) | (Protocol::Udp, SocketProtocol::Tcp)
+    )
+}
+
+/// The worst state among the listeners: an unrelated process beats an
+/// unknown one, which beats the expected one.
+fn classify(name: &str, listeners: &[Listener]) -> PortState {
+    listeners
+        .iter()
+        .map(|l| match &l.process {
+            Some(process) if is_expected(name, process) => PortState::Expected,
+            Some(_) => PortState::Other,
+            None => PortState::InUse,
+        })
+        .max_by_key(|state| match state {
+            PortState::Free => 0,
+            PortState::Expected => 1,
+            PortState::InUse => 2,
+            PortState::Other => 3,
+        })
+        .unwrap_or(PortState::Free)
+}
+
+/// Heuristic match between a mapping name and a process: `postgres` matches
+/// `postgres`, `redis` matches `redis-server`, and `myapp` matches any
+/// process started with `myapp` on its command line.
+pub fn is_expected(name: &str, process: &ProcessInfo) -> bool {
+    let key = simplify(name);
+    let comm = simplify(&process.name);
+    if key.is_empty() || comm.is_empty() {
+        return false;
+    }
+    comm.contains(&key)
+        || (comm.len() >= 3 && key.contains(&comm))
+        || simplify(&process.cmdline).contains(&key)
+}
+
+fn simplify(value: &str) -> String {
+    value
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Renders the status table.
+pub fn render_text(statuses: &[PortStatus], color: bool) -> String {
+    let mut out = format!(
+        "{:<24} {:<13} {:<9} {}\n{}\n",
+        "Name",
+        "Port",
+        "State",
+        "Process",
+        "-".repeat(67)
+    );
+    for status in statuses {
+        let port = match (status.port, status.start, status.end) {
+            (Some(port), _, _) => port.to_string(),
+            (None, Some(start), Some(end)) => format!("{}-{}", start, end),
+            _ => "-".to_string(),
+        };
+        let name = match &status.project {
+            Some(project) => format!("{}/{}", project, status.name),
+            None => status.name.clone(),
+        };
+        let state = format!("{:<9}", status.state.label());
+        let state = if color {
+            format!("\x1b[{}m{}\x1b[0m", status.state.color(), state)
+        } else {
+            state
+        };
+        out.push_str(&format!(
+            "{:<24} {:<13} {} {}\n",
+            name,
+            port,
+            state,
+            describe_listeners(&status.listeners)
+        ));
+    }
+    out
+}
+
+fn describe_listeners(listeners: &[Listener]) -> String {
+    let mut seen: Vec<String> = Vec::new();
+    for listener in listeners {
+        let owner = match &listener.process {
+            Some(process) => format!("{} ({})", process.name, process.pid),
+            None => format!("uid {}", listener.uid),
+        };
+        if !seen.contains(&owner) {
+            seen.push(owner);
+        }
+    }
+    if seen.is_empty() {
+        "-".to_string()
+    } else {
+        seen.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listener(port: u16, process: Option<(&str, &str)>) -> Listener {
+        Listener {
+            protocol: SocketProtocol::Tcp,
+            address: format!("127.0.0.1:{}", port).parse().unwrap(),
+            uid: 1000,
+            inode: 1,
+            process: process.map(|(name, cmdline)| ProcessInfo {
+                pid: 42,
+                name: name.to_string(),
+                cmdline: cmdline.to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_is_expected() {
+        let process = |name: &str, cmdline: &str| ProcessInfo {
+            pid: 1,
+            name: name.to_string(),
+            cmdline: cmdline.to_string(),
+        };
+        assert!(is_expected(
+            "redis",
+            &process("redis-server", "redis-server *:6379")
+        ));
+        assert!(is_expected(
+            "postgresql",
+            &process("postgres", "postgres -D /data")
+        ));
+        assert!(is_expected(
+            "my-app",
+            &process("node", "node /srv/myapp/server.js")
+        ));
+        assert!(!is_expected("redis", &process("node", "node server.js")));
+    }
+
+    #[test]
+    fn test_check_states() {
+        let redis = PortMapping::new("redis", PortSpec::Single(6379), None);
+        let listeners = vec![
+            listener(6379, Some(("redis-server", "redis-server"))),
+            listener(8080, Some(("python3", "python3 -m http.server"))),
+            listener(9000, None),
+        ];
+
+        let status = check(&redis, MappingSource::Default, Some(&listeners));
+        assert_eq!(status.state, PortState::Expected);
+        assert_eq!(status.listeners.len(), 1);
+
+        let web = PortMapping::new("web", PortSpec::Single(8080), None);
+        let status = check(&web, MappingSource::User, Some(&listeners));
+        assert_eq!(status.state, PortState::Other);
+
+        let pool = PortMapping::new(
+            "pool",
+            PortSpec::Range {
+                start: 8990,
+                end: 9010,
+            },
+            None,
+        );
+        let status = check(&pool, MappingSource::User, Some(&listeners));
+        assert_eq!(status.state, PortState::InUse);
+        assert_eq!((status.start, status.end), (Some(8990), Some(9010)));
+
+        // A TCP listener doesn't conflict with a UDP service
+        let mut dns = PortMapping::new("dns", PortSpec::Single(8080), None);
+        dns.protocol = Protocol::Udp;
+        let status = check(&dns, MappingSource::User, Some(&listeners));
+        assert_eq!(status.state, PortState::Free);
+
+        let free = PortMapping::new("docs", PortSpec::Single(4000), None);
+        let status = check(&free, MappingSource::User, Some(&listeners));
+        assert_eq!(status.state, PortState::Free);
+        assert!(status.listeners.is_empty());
+    }
+
+    #[test]
+    fn test_render_text_colors() {
+        let web = PortMapping::new("web", PortSpec::Single(8080), None);
+        let statuses = vec![check(&web, MappingSource::User, Some(&[]))];
+
+        let plain = render_text(&statuses, false);
+        assert!(plain.lines().nth(2).unwrap().starts_with("web"));
+        assert!(!plain.contains('\x1b'));
+        assert!(render_text(&statuses, true).contains("\x1b[2mfree"));
+    }
+
+    #[test]
+    fn test_status_json() {
+        let web = PortMapping::new("web", PortSpec::Single(8080), None);
+        let listeners = vec![listener(8080, Some(("node", "node web.js")))];
+        let status = check(&web, MappingSource::User, Some(&listeners));
+
+        let value = serde_json::to_value(&status).unwrap();
+        assert_eq!(value["state"], "expected");
+        assert_eq!(value["listeners"][0]["process"]["pid"], 42);
+        assert_eq!(value["listeners"][0]["address"], "127.0.0.1:8080");
+    }
+}
@@ -0,0 +1,298 @@
+use crate::config::PortMapping;
+use std::fmt;
+
+/// One difference between defaults.toml and the merged result of `sync-defaults`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// New built-in default
+    Added(PortMapping),
+    /// Built-in default that was dropped and hadn't been edited locally
+    Removed(PortMapping),
+    /// Unedited default whose built-in version changed
+    Updated {
+        before: PortMapping,
+        after: PortMapping,
+    },
+    /// Default renamed in the built-ins; local edits are carried over
+    Renamed { from: String, to: PortMapping },
+    /// Locally edited default whose built-in changed (or was dropped); the
+    /// local version wins
+    Kept {
+        local: PortMapping,
+        builtin: Option<PortMapping>,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(mapping) => write!(f, "+ {}", describe(mapping)),
+            Change::Removed(mapping) => write!(f, "- {}", describe(mapping)),
+            Change::Updated { before, after } => {
+                write!(f, "~ {}: {}", after.name, describe_diff(before, after))
+            }
+            Change::Renamed { from, to } => write!(f, "> {} renamed to {}", from, describe(to)),
+            Change::Kept {
+                local,
+                builtin: Some(builtin),
+            } => write!(
+                f,
+                "! {}: kept your edit (built-in {})",
+                describe(local),
+                describe_diff(local, builtin)
+            ),
+            Change::Kept {
+                local,
+                builtin: None,
+            } => write!(
+                f,
+                "! {}: kept your edit (no longer a built-in default)",
+                describe(local)
+            ),
+        }
+    }
+}
+
+fn describe(mapping: &PortMapping) -> String {
+    format!("{} ({})", mapping.name, mapping.port.display())
+}
+
+/// What differs between two versions of a default, e.g. `port 8080 -> 8081`.
+fn describe_diff(before: &PortMapping, after: &PortMapping) -> String {
+    let mut parts = Vec::new();
+    if before.port != after.port {
+        parts.push(format!(
+            "port {} -> {}",
+            before.port.display(),
+            after.port.display()
+        ));
+    }
+    if before.description != after.description {
+        parts.push(format!(
+            "description {:?}",
+            after.description.as_deref().unwrap_or("")
+        ));
+    }
+    if before.protocol != after.protocol {
+        parts.push(format!("protocol {}", after.protocol.as_str()));
+    }
+    if parts.is_empty() {
+        parts.push("unchanged".to_string());
+    }
+    parts.join(", ")
+}
+
+/// Result of [`merge`]: the new defaults and what changed.
+#[derive(Debug)]
+pub struct Merge {
+    pub ports: Vec<PortMapping>,
+    pub changes: Vec<Change>,
+}
+
+/// Three-way merge of `local` defaults with the built-in defaults, given the
+/// built-ins the file was created from (`base`) and the current ones (`new`).
+///
+/// Entries the user edited or added are kept, entries they deleted stay
+/// deleted, and unedited entries follow the built-ins. `renames` maps old
+/// built-in names to new ones.
+pub fn merge(
+    base: &[PortMapping],
+    new: &[PortMapping],
+    local: &[PortMapping],
+    renames: &[(&str, &str)],
+) -> Merge {
+    let find = |list: &'_ [PortMapping], name: &str| -> Option<PortMapping> {
+        list.iter().find(|p| p.name == name).cloned()
+    };
+    let mut ports: Vec<PortMapping> = Vec::new();
+    let mut changes = Vec::new();
+
+    for entry in local {
+        let base_entry = find(base, &entry.name);
+        let edited = base_entry.as_ref() != Some(entry);
+
+        let renamed_to = renames
+            .iter()
+            .find(|(old, _)| *old == entry.name)
+            .map(|(_, new_name)| *new_name)
+            .filter(|new_name| find(local, new_name).is_none());
+        if let Some(new_name) = renamed_to {
+            let renamed = match find(new, new_name) {
+                Some(builtin) if !edited => builtin,
+                _ => PortMapping {
+                    name: new_name.to_string(),
+                    ..entry.clone()
+                },
+            };
+            changes.push(Change::Renamed {
+                from: entry.name.clone(),
+                to: renamed.clone(),
+            });
+            ports.push(renamed);
+            continue;
+        }
+
+        match (base_entry, find(new, &entry.name)) {
+            (Some(_), Some(builtin)) if !edited => {
+                if builtin != *entry {
+                    changes.push(Change::Updated {
+                        before: entry.clone(),
+                        after: builtin.clone(),
+                    });
+                }
+                ports.push(builtin);
+            }
+            (Some(base_entry), Some(builtin)) => {
+                if builtin != base_entry && builtin != *entry {
+                    changes.push(Change::Kept {
+                        local: entry.clone(),
+                        builtin: Some(builtin),
+                    });
+                }
+                ports.push(entry.clone());
+            }
+            (Some(_), None) if !edited => changes.push(Change::Removed(entry.clone())),
+            (Some(_), None) => {
+                changes.push(Change::Kept {
+                    local: entry.clone(),
+                    builtin: None,
+                });
+                ports.push(entry.clone());
+            }
+            // Added by the user, or already up to date
+            (None, _) => ports.push(entry.clone()),
+        }
+    }
+
+    for builtin in new {
+        let known = ports.iter().any(|p| p.name == builtin.name)
+            || find(base, &builtin.name).is_some()
+            || renames
+                .iter()
+                .any(|(old, to)| *to == builtin.name && find(base, old).is_some());
+        // Known entries missing from `ports` were deleted by the user
+        if !known {
+            changes.push(Change::Added(builtin.clone()));
+            ports.push(builtin.clone());
+        }
+    }
+
+    Merge { ports, changes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortSpec;
+
+    fn mapping(name: &str, port: u16) -> PortMapping {
+        PortMapping::new(name, PortSpec::Single(port), None)
+    }
+
+    #[test]
+    fn test_merge_keeps_local_edits_and_adds_new() {
+        let base = vec![mapping("postgres", 5432), mapping("redis", 6379)];
+        let new = vec![
+            mapping("postgres", 5432),
+            mapping("redis", 6380),
+            mapping("nats", 4222),
+        ];
+        let local = vec![mapping("postgres", 5433), mapping("redis", 6379)];
+
+        let merged = merge(&base, &new, &local, &[]);
+        let ports: Vec<_> = merged
+            .ports
+            .iter()
+            .map(|p| (&*p.name, p.port.clone()))
+            .collect();
+        assert_eq!(
+            ports,
+            vec![
+                ("postgres", PortSpec::Single(5433)),
+                ("redis", PortSpec::Single(6380)),
+                ("nats", PortSpec::Single(4222)),
+            ]
+        );
+        assert_eq!(
+            merged.changes,
+            vec![
+                Change::Updated {
+                    before: mapping("redis", 6379),
+                    after: mapping("redis", 6380),
+                },
+                Change::Added(mapping("nats", 4222)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_removals_and_conflicts() {
+        let base = vec![
+            mapping("old", 1000),
+            mapping("edited", 2000),
+            mapping("deleted", 3000),
+            mapping("web", 8080),
+        ];
+        let new = vec![mapping("deleted", 3000), mapping("web", 8081)];
+        let local = vec![
+            mapping("old", 1000),
+            mapping("edited", 2001),
+            mapping("web", 9090),
+            mapping("mine", 7000),
+        ];
+
+        let merged = merge(&base, &new, &local, &[]);
+        let names: Vec<_> = merged.ports.iter().map(|p| p.name.as_str()).collect();
+        // Unedited removals go, local edits and additions stay, deletions stay deleted
+        assert_eq!(names, vec!["edited", "web", "mine"]);
+        assert_eq!(
+            merged.changes,
+            vec![
+                Change::Removed(mapping("old", 1000)),
+                Change::Kept {
+                    local: mapping("edited", 2001),
+                    builtin: None,
+                },
+                Change::Kept {
+                    local: mapping("web", 9090),
+                    builtin: Some(mapping("web", 8081)),
+                },
+            ]
+        );
+        assert_eq!(
+            merged.changes[2].to_string(),
+            "! web (9090): kept your edit (built-in port 9090 -> 8081)"
+        );
+    }
+
+    #[test]
+    fn test_merge_renames() {
+        let base = vec![mapping("elastic", 9200), mapping("mongo", 27017)];
+        let new = vec![mapping("elasticsearch", 9200), mapping("mongodb", 27017)];
+        let local = vec![mapping("elastic", 9201), mapping("mongo", 27017)];
+
+        let merged = merge(
+            &base,
+            &new,
+            &local,
+            &[("elastic", "elasticsearch"), ("mongo", "mongodb")],
+        );
+        assert_eq!(
+            merged.ports,
+            vec![mapping("elasticsearch", 9201), mapping("mongodb", 27017)]
+        );
+        assert_eq!(
+            merged.changes[0].to_string(),
+            "> elastic renamed to elasticsearch (9201)"
+        );
+        assert_eq!(merged.changes.len(), 2);
+    }
+
+    #[test]
+    fn test_merge_up_to_date_is_noop() {
+        let defaults = vec![mapping("postgres", 5432), mapping("redis", 6379)];
+        let merged = merge(&defaults, &defaults, &defaults, &[]);
+        assert_eq!(merged.ports, defaults);
+        assert!(merged.changes.is_empty());
+    }
+}